@@ -3,8 +3,12 @@ use prettytable::{
     Table,
 };
 use std::io::{self, Write};
+use zeroize::Zeroizing;
 
+use super::hardening;
+use super::history::PasswordHistory;
 use super::logindata;
+use super::metadata::KeySlot;
 
 pub fn input(message: &str, allow_empty: bool) -> String {
     print!("{message}");
@@ -17,11 +21,40 @@ pub fn input(message: &str, allow_empty: bool) -> String {
     }
 }
 
-pub fn init_master_password() -> String {
+/// Ask for a text, keeping `current` on empty input.
+pub fn input_or_keep(message: &str, current: &str) -> String {
+    let value = input(&format!("{message} [{current}]: "), true);
+    match value.is_empty() {
+        true => current.to_owned(),
+        false => value,
+    }
+}
+
+/// Ask for a number, keeping `default` on empty or invalid input.
+pub fn input_number(message: &str, default: u32) -> u32 {
+    let value = input(&format!("{message} [{default}]: "), true);
+    if value.is_empty() {
+        return default;
+    }
+    value.parse().unwrap_or_else(|_| {
+        println!("Invalid number, keeping {default}.");
+        default
+    })
+}
+
+/// Ask for a password without echoing it.
+/// The returned string is locked into RAM and wiped from memory when dropped.
+pub fn password(message: &str) -> Zeroizing<String> {
+    let password = Zeroizing::new(rpassword::prompt_password(message).unwrap());
+    hardening::lock_memory(password.as_str());
+    password
+}
+
+pub fn init_master_password() -> Zeroizing<String> {
     loop {
         println!("Welcome! Let's create your master password first.");
-        let password = rpassword::prompt_password("Create a master password: ").unwrap();
-        if rpassword::prompt_password("Retype password: ").unwrap() == password {
+        let password = password("Create a master password: ");
+        if *self::password("Retype password: ") == *password {
             println!("Thank you! Now you can continue. Have a nice day!\n");
             return password;
         } else {
@@ -30,13 +63,49 @@ pub fn init_master_password() -> String {
     }
 }
 
+pub fn new_master_password() -> Zeroizing<String> {
+    loop {
+        let password = password("New master password: ");
+        if *self::password("Retype new password: ") == *password {
+            return password;
+        } else {
+            println!("Password not match. Please try again\n");
+        }
+    }
+}
+
+/// Ask for the duress password twice. Empty removes the decoy vault.
+pub fn new_duress_password() -> Zeroizing<String> {
+    loop {
+        let password = password("Duress password (empty to remove the decoy vault): ");
+        if password.is_empty() || *self::password("Retype duress password: ") == *password {
+            return password;
+        } else {
+            println!("Password not match. Please try again\n");
+        }
+    }
+}
+
 pub fn main_menu_text() {
     println!("Hi there! Select an operation:");
     println!("  ▶ insert\tInsert new item to the database");
     println!("  ▶ display\tDisplay all accounts");
     println!("  ▶ search\tSearch for an item");
     println!("  ▶ export\tDecrypt and export all items to csv file");
-    println!("  ▶ (number)\tSelect the item with ID (number) to apply actions");
+    println!("  ▶ passwd\tChange the master password");
+    println!("  ▶ kdf\t\tUpgrade the key derivation parameters");
+    println!("  ▶ rotate-key\tRe-encrypt all items under a new encryption key");
+    println!("  ▶ slots\tList the key slots opening the vault");
+    println!("  ▶ add-slot\tAdd a key slot with its own password");
+    println!("  ▶ label-slot\tRename a key slot");
+    println!("  ▶ revoke-slot\tRemove a key slot");
+    println!("  ▶ lockout\tRequire a keyfile or the recovery key after failed unlocks");
+    println!("  ▶ split-key\tSplit a new recovery key into shares for emergency access");
+    println!("  ▶ trash\tDisplay the items in the trash");
+    println!("  ▶ restore\tRestore an item from the trash");
+    println!("  ▶ empty-trash\tDelete the items in the trash for good");
+    println!("  ▶ retention\tSet how long items stay in the trash");
+    println!("  ▶ (number)\tSelect the item numbered (number) in the list to apply actions");
     println!("  ▶ quit\tQuit the program");
 }
 
@@ -45,20 +114,21 @@ pub fn main_menu_selection_prompt() {
     io::stdout().flush().unwrap();
 }
 
-pub fn item_operation_prompt(id: usize, name: &str) {
-    println!("Select an operation on item no.{} ({}) :", id, name);
-    println!("  ▶ remove\tRemove this item");
+pub fn item_operation_prompt(index: usize, item: &logindata::LoginData) {
+    println!(
+        "Select an operation on item no.{} ({}, ID {}) :",
+        index, item.name, item.uuid
+    );
+    println!("  ▶ remove\tMove this item to the trash");
     println!("  ▶ update\tUpdate information of this item");
     println!("  ▶ password\tPrint the decrypted password");
+    println!("  ▶ history\tShow or restore a previous password");
     println!("Any other keys to get back.");
-    print!("Select operation [remove/update/password]: ");
+    print!("Select operation [remove/update/password/history]: ");
     io::stdout().flush().unwrap();
 }
 
-pub fn print_table<'a, I>(data: I)
-where
-    I: Iterator<Item = &'a logindata::LoginData>,
-{
+fn new_table() -> Table {
     let mut table = Table::new();
     let format = FormatBuilder::new()
         .column_separator('│')
@@ -79,14 +149,48 @@ where
         .padding(1, 1)
         .build();
     table.set_format(format);
-    table.set_titles(row!["ID", "LOGIN", "USERNAME", "DATE MODIFIED"]);
-    for item in data {
+    table
+}
+
+/// Print `(display index, item)` pairs. The index is only the position in the menu,
+/// items are identified by their UUID.
+pub fn print_table<'a, I>(data: I)
+where
+    I: Iterator<Item = (usize, &'a logindata::LoginData)>,
+{
+    let mut table = new_table();
+    table.set_titles(row!["NO.", "LOGIN", "USERNAME", "DATE MODIFIED"]);
+    for (index, item) in data {
+        table.add_row(row![index, &item.name, &item.username, &item.date_modified]);
+    }
+    table.printstd();
+}
+
+/// Print the items in the trash, numbered like `print_table`.
+pub fn print_trash(trash: &[logindata::LoginData]) {
+    let mut table = new_table();
+    table.set_titles(row!["NO.", "LOGIN", "USERNAME", "DATE DELETED"]);
+    for (index, item) in (1..).zip(trash) {
         table.add_row(row![
-            &item.id,
+            index,
             &item.name,
             &item.username,
-            &item.date_modified
+            item.date_deleted.as_deref().unwrap_or_default()
         ]);
     }
     table.printstd();
 }
+
+pub fn print_history(entries: &[PasswordHistory]) {
+    println!("Previous passwords:");
+    for (index, entry) in entries.iter().enumerate() {
+        println!("  {}. replaced on {}", index + 1, entry.date_retired);
+    }
+}
+
+pub fn print_key_slots(slots: &[KeySlot]) {
+    println!("Key slots:");
+    for (index, slot) in slots.iter().enumerate() {
+        println!("  {}. {slot}", index + 1);
+    }
+}
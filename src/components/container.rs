@@ -0,0 +1,185 @@
+//! Single-file vault, holding the metadata and the database of a vault together.
+//!
+//! Layout:
+//! 1. magic: `PGVAULT\0`
+//! 2. version: one byte, see `VERSION`.
+//! 3. header: length as `u32` little-endian, then the metadata file as is, key slots included.
+//! 4. payload: the database file encrypted under the export subkey with `Cipher::encrypt_for`,
+//!    everything before it as associated data, so the header cannot be swapped or altered
+//!    either.
+//!
+//! While a single-file vault is open it is unpacked into the usual two-file layout inside a
+//! private working folder and packed again after every change.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use zeroize::Zeroizing;
+
+use super::cryptography::encryption::{Cipher, Purpose};
+use super::error::DataStorageError;
+
+const MAGIC: &[u8; 8] = b"PGVAULT\0";
+/// Version of the layout.
+/// 1. Initial layout.
+const VERSION: u8 = 1;
+
+/// A single-file vault as read from disk.
+pub struct Container {
+    /// Everything before the payload.
+    header: Vec<u8>,
+    payload: String,
+}
+
+impl Container {
+    pub fn read(path: &Path) -> Result<Self, DataStorageError> {
+        if !path.is_file() {
+            return Err(DataStorageError::FileNotFound {
+                path: path.to_path_buf(),
+            });
+        }
+        let content = fs::read(path)?;
+        let corrupted = |cause: &str| DataStorageError::ContainerCorrupted {
+            cause: cause.into(),
+        };
+
+        if content.len() < MAGIC.len() + 5 || !content.starts_with(MAGIC) {
+            return Err(corrupted("it is not a vault file."));
+        }
+        let version = content[MAGIC.len()];
+        if version > VERSION {
+            return Err(corrupted("it was written by a newer version."));
+        }
+        let length_at = MAGIC.len() + 1;
+        let length: [u8; 4] = content[length_at..length_at + 4].try_into().unwrap();
+        let payload_at = (length_at + 4).saturating_add(u32::from_le_bytes(length) as usize);
+        if payload_at > content.len() {
+            return Err(corrupted("it is truncated."));
+        }
+        let payload = String::from_utf8(content[payload_at..].to_vec())
+            .map_err(|_| corrupted("its payload is not readable."))?;
+        Ok(Self {
+            header: content[..payload_at].to_vec(),
+            payload,
+        })
+    }
+
+    /// The metadata file of the vault.
+    pub fn metadata(&self) -> &[u8] {
+        &self.header[MAGIC.len() + 5..]
+    }
+
+    /// Decrypt the database file. Fails if the vault file was altered.
+    pub fn database(&self, cipher: &Cipher) -> Result<Zeroizing<Vec<u8>>, DataStorageError> {
+        cipher
+            .decrypt_for(Purpose::Export, &self.payload, &self.header)
+            .map_err(|_| DataStorageError::ContainerCorrupted {
+                cause: "its content does not match its header, it has been tampered with.".into(),
+            })
+    }
+}
+
+/// Write the vault at `metadata_path` and `database_path` into a single file at `path`.
+/// The file is replaced atomically, it is never left half-written.
+pub fn pack(
+    path: &Path,
+    metadata_path: &Path,
+    database_path: &Path,
+    cipher: &Cipher,
+) -> Result<(), DataStorageError> {
+    let metadata = fs::read(metadata_path)?;
+    let database = Zeroizing::new(fs::read(database_path)?);
+
+    let mut header = MAGIC.to_vec();
+    header.push(VERSION);
+    header.extend((metadata.len() as u32).to_le_bytes());
+    header.extend(metadata);
+    let payload = cipher.encrypt_for(Purpose::Export, &database, &header);
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&header)?;
+    file.write_all(payload.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Write the metadata of the vault file at `path` to `metadata_path`.
+/// Its database can only be unpacked once the metadata unlocks.
+pub fn unpack_metadata(path: &Path, metadata_path: &Path) -> Result<(), DataStorageError> {
+    fs::write(metadata_path, Container::read(path)?.metadata())?;
+    Ok(())
+}
+
+/// Decrypt the database of the vault file at `path` to `database_path`.
+pub fn unpack_database(
+    path: &Path,
+    cipher: &Cipher,
+    database_path: &Path,
+) -> Result<(), DataStorageError> {
+    let database = Container::read(path)?.database(cipher)?;
+    fs::write(database_path, database.as_slice())?;
+    Ok(())
+}
+
+/// The working folder created by this process, if any.
+static WORKING_FOLDER: OnceLock<PathBuf> = OnceLock::new();
+
+/// Create a working folder only the current user can access.
+/// `remove_working_folder` deletes it with the unpacked vault inside.
+pub fn working_folder() -> Result<PathBuf, DataStorageError> {
+    let path = std::env::temp_dir().join(format!("password-guardian-{}", std::process::id()));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&path)?;
+    _ = WORKING_FOLDER.set(path.clone());
+    Ok(path)
+}
+
+/// Delete the working folder, if one was created. Call it on every way out of the program.
+pub fn remove_working_folder() {
+    if let Some(path) = WORKING_FOLDER.get() {
+        _ = fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let folder = working_folder().unwrap();
+        let (metadata_path, database_path) = (folder.join("meta.json"), folder.join("data.db"));
+        let path = folder.join("vault.pgv");
+        fs::write(&metadata_path, br#"{"slots":[]}"#).unwrap();
+        fs::write(&database_path, b"database").unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        pack(&path, &metadata_path, &database_path, &cipher).unwrap();
+
+        let container = Container::read(&path).unwrap();
+        assert_eq!(container.metadata(), br#"{"slots":[]}"#);
+        assert_eq!(*container.database(&cipher).unwrap(), b"database");
+        assert!(container.database(&Cipher::new(&[2u8; 32])).is_err());
+        // The payload has a subkey of its own
+        assert!(cipher.decrypt(&container.payload, &container.header).is_err());
+
+        // The header is authenticated with the payload
+        let mut content = fs::read(&path).unwrap();
+        content[MAGIC.len() + 6] = b'[';
+        fs::write(&path, &content).unwrap();
+        assert!(Container::read(&path).unwrap().database(&cipher).is_err());
+
+        content[MAGIC.len()] = VERSION + 1;
+        fs::write(&path, &content).unwrap();
+        assert!(Container::read(&path).is_err());
+        fs::write(&path, b"SQLite format 3\0").unwrap();
+        assert!(Container::read(&path).is_err());
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
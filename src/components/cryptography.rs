@@ -1,48 +1,398 @@
 use getrandom::getrandom;
+use zeroize::Zeroizing;
 pub fn generate_salt() -> [u8; 16] {
     let mut dest = [0u8; 16];
     getrandom(&mut dest).unwrap();
     dest
 }
+/// Random 256-bit key, wiped from memory when dropped.
+pub fn generate_key() -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    getrandom(key.as_mut()).unwrap();
+    key
+}
 pub mod encryption {
     use aes_kw::KekAes256;
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::{XChaCha20Poly1305, XNonce};
     use fernet::{DecryptionError, Fernet};
+    use getrandom::getrandom;
+    use hmac_sha256::{HKDF, HMAC};
+    use serde::{Deserialize, Serialize};
+    use zeroize::Zeroizing;
+
+    use crate::components::hardening::{self, Locked};
+
+    /// Authenticated encryption algorithms.
+    /// Ciphertexts are stored as `tag:payload`; values without a tag are legacy Fernet tokens.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Algorithm {
+        /// AES-128-CBC with HMAC-SHA256.
+        Fernet,
+        /// XChaCha20-Poly1305 with a random 192-bit nonce.
+        XChaCha20Poly1305,
+    }
+
+    impl Algorithm {
+        /// Algorithm used for new ciphertexts.
+        pub const CURRENT: Algorithm = Algorithm::XChaCha20Poly1305;
+
+        pub fn tag(&self) -> &'static str {
+            match self {
+                Algorithm::Fernet => "fernet",
+                Algorithm::XChaCha20Poly1305 => "xchacha20poly1305",
+            }
+        }
+
+        /// Split a stored ciphertext into its algorithm and payload.
+        pub fn parse(ciphertext: &str) -> Option<(Self, &str)> {
+            let Some((tag, payload)) = ciphertext.split_once(':') else {
+                return Some((Algorithm::Fernet, ciphertext));
+            };
+            let algorithm = [Algorithm::Fernet, Algorithm::XChaCha20Poly1305]
+                .into_iter()
+                .find(|algorithm| algorithm.tag() == tag)?;
+            Some((algorithm, payload))
+        }
+    }
+
+    /// An authenticated encryption algorithm keyed with the encryption key.
+    trait Backend {
+        fn encrypt(&self, data: &[u8], associated_data: &[u8]) -> String;
+        fn decrypt(
+            &self,
+            payload: &str,
+            associated_data: &[u8],
+        ) -> Result<Vec<u8>, DecryptionError>;
+    }
+
+    /// Fernet cannot authenticate associated data, it is only kept to migrate legacy tokens.
+    impl Backend for Fernet {
+        fn encrypt(&self, data: &[u8], _: &[u8]) -> String {
+            Fernet::encrypt(self, data)
+        }
+
+        fn decrypt(&self, payload: &str, _: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+            Fernet::decrypt(self, payload)
+        }
+    }
+
+    /// Payload is `base64url(NONCE || CIPHERTEXT || TAG)`.
+    impl Backend for XChaCha20Poly1305 {
+        fn encrypt(&self, data: &[u8], associated_data: &[u8]) -> String {
+            let mut nonce = [0u8; 24];
+            getrandom(&mut nonce).unwrap();
+            let payload = Payload {
+                msg: data,
+                aad: associated_data,
+            };
+            let ciphertext = Aead::encrypt(self, XNonce::from_slice(&nonce), payload).unwrap();
+            base64_url::encode(&[&nonce[..], &ciphertext].concat())
+        }
+
+        fn decrypt(
+            &self,
+            payload: &str,
+            associated_data: &[u8],
+        ) -> Result<Vec<u8>, DecryptionError> {
+            let payload = base64_url::decode(payload).map_err(|_| DecryptionError)?;
+            if payload.len() < 24 {
+                return Err(DecryptionError);
+            }
+            let (nonce, ciphertext) = payload.split_at(24);
+            let payload = Payload {
+                msg: ciphertext,
+                aad: associated_data,
+            };
+            Aead::decrypt(self, XNonce::from_slice(nonce), payload).map_err(|_| DecryptionError)
+        }
+    }
+
+    /// How purpose-specific subkeys are derived from the vault key `VKEY`.
+    /// Recorded in the metadata as its version number.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(try_from = "u32", into = "u32")]
+    pub enum KeyScheme {
+        /// 1: `VKEY` encrypts as is, other subkeys are `HMAC-SHA256(VKEY, purpose)`.
+        Legacy,
+        /// 2: `PRK = HKDF-Extract("password-guardian", VKEY)`,
+        /// every subkey is `HKDF-Expand(PRK, "password-guardian v2 " || purpose)`.
+        Hkdf,
+    }
+
+    impl KeyScheme {
+        /// Scheme used for new vaults, older ones are upgraded after unlock.
+        pub const CURRENT: KeyScheme = KeyScheme::Hkdf;
+
+        pub fn is_legacy(&self) -> bool {
+            *self == KeyScheme::Legacy
+        }
+    }
+
+    impl TryFrom<u32> for KeyScheme {
+        type Error = String;
+
+        fn try_from(version: u32) -> Result<Self, Self::Error> {
+            match version {
+                1 => Ok(KeyScheme::Legacy),
+                2 => Ok(KeyScheme::Hkdf),
+                _ => Err(format!("unknown key derivation scheme {version}")),
+            }
+        }
+    }
+
+    impl From<KeyScheme> for u32 {
+        fn from(scheme: KeyScheme) -> Self {
+            match scheme {
+                KeyScheme::Legacy => 1,
+                KeyScheme::Hkdf => 2,
+            }
+        }
+    }
+
+    /// What a subkey is used for. No two purposes share key material.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Purpose {
+        /// Encrypting stored fields, see `Algorithm`.
+        Encryption,
+        /// Authenticating the database content, see `integrity`.
+        Integrity,
+        /// Authenticating the metadata file.
+        Metadata,
+        /// Wrapping the page key of an encrypted database file.
+        Database,
+        /// Reserved for searching encrypted fields by keyed hash.
+        #[allow(dead_code)]
+        BlindIndex,
+        /// Encrypting the database of single-file vaults, see `container`.
+        Export,
+    }
+
+    impl Purpose {
+        fn label(&self) -> &'static str {
+            match self {
+                Purpose::Encryption => "encryption",
+                Purpose::Integrity => "integrity",
+                Purpose::Metadata => "metadata",
+                Purpose::Database => "database",
+                Purpose::BlindIndex => "blind-index",
+                Purpose::Export => "export",
+            }
+        }
+    }
 
     /// The cipher that encrypts the passwords.
+    /// All key material is wiped from memory when it is dropped. The vault key and the
+    /// XChaCha20-Poly1305 key are kept in locked memory; Fernet keeps its keys in buffers of
+    /// its own, so it is only built when a value being migrated needs it.
     pub struct Cipher {
-        fernet: Fernet,
+        xchacha: Box<XChaCha20Poly1305>,
+        key: Locked<[u8; 32]>,
+        scheme: KeyScheme,
     }
 
     impl Cipher {
+        /// Generate cipher from the raw vault key with the current key scheme.
+        pub fn new(key: &[u8; 32]) -> Self {
+            Self::with_scheme(key, KeyScheme::CURRENT)
+        }
+
+        /// Generate cipher from the raw vault key, deriving subkeys with `scheme`.
+        /// Fernet uses the encryption subkey `EKEY` as is, other algorithms use
+        /// `HMAC-SHA256(EKEY, tag)`.
+        pub fn with_scheme(key: &[u8; 32], scheme: KeyScheme) -> Self {
+            let encryption_key = derive_subkey(key, scheme, Purpose::Encryption);
+            let xchacha_key = Zeroizing::new(HMAC::mac(
+                Algorithm::XChaCha20Poly1305.tag(),
+                encryption_key.as_ref(),
+            ));
+            let xchacha = Box::new(XChaCha20Poly1305::new(xchacha_key.as_ref().into()));
+            hardening::lock_memory(&*xchacha);
+            Self {
+                xchacha,
+                key: Locked::new(*key),
+                scheme,
+            }
+        }
+
+        /// Generate cipher with a new random vault key.
+        pub fn generate() -> Self {
+            Self::new(&super::generate_key())
+        }
+
         /// Generate cipher by hashing password as KEK and unwrap WRAP
-        /// `EKEY = AES-KW^-1(WRAP, Argon2(PASS, SALT))`
-        pub fn from_unwrap(kek: [u8; 32], wrapped_key: [u8; 40]) -> Result<Self, aes_kw::Error> {
-            let kek_cipher = KekAes256::from(kek);
-            let mut decrypted_key = [0u8; 32];
-            kek_cipher.unwrap(&wrapped_key, &mut decrypted_key)?;
-            let fernet = Fernet::new(&base64_url::encode(&decrypted_key)).unwrap();
+        /// `VKEY = AES-KW^-1(WRAP, Argon2(PASS, SALT))`
+        pub fn from_unwrap(
+            kek: &[u8; 32],
+            wrapped_key: [u8; 40],
+            scheme: KeyScheme,
+        ) -> Result<Self, aes_kw::Error> {
+            let kek_cipher = KekAes256::from(*kek);
+            let mut decrypted_key = Locked::new([0u8; 32]);
+            kek_cipher.unwrap(&wrapped_key, decrypted_key.as_mut())?;
+
+            Ok(Self::with_scheme(&decrypted_key, scheme))
+        }
+
+        /// The same vault key with subkeys derived by `scheme`.
+        pub fn rederive(&self, scheme: KeyScheme) -> Self {
+            Self::with_scheme(&self.key, scheme)
+        }
+
+        pub fn scheme(&self) -> KeyScheme {
+            self.scheme
+        }
+
+        /// Wrap the vault key under KEK
+        /// `WRAP = AES-KW(VKEY, KEK)`
+        pub fn wrap(&self, kek: &[u8; 32]) -> [u8; 40] {
+            let kek_cipher = KekAes256::from(*kek);
+            let mut wrap = [0u8; 40];
+            kek_cipher.wrap(self.key.as_ref(), &mut wrap).unwrap();
+            wrap
+        }
+
+        /// Subkey for `purpose`, derived from the vault key with the key scheme of the vault.
+        pub fn subkey(&self, purpose: Purpose) -> Zeroizing<[u8; 32]> {
+            derive_subkey(&self.key, self.scheme, purpose)
+        }
+
+        /// Wrap another key under the subkey for `purpose`
+        /// `WRAP = AES-KW(key, subkey(purpose))`
+        pub fn wrap_key(&self, purpose: Purpose, key: &[u8; 32]) -> [u8; 40] {
+            let kek_cipher = KekAes256::from(*self.subkey(purpose));
+            let mut wrap = [0u8; 40];
+            kek_cipher.wrap(key, &mut wrap).unwrap();
+            wrap
+        }
+
+        /// Unwrap a key wrapped by `wrap_key` with the same `purpose`.
+        pub fn unwrap_key(
+            &self,
+            purpose: Purpose,
+            wrapped_key: [u8; 40],
+        ) -> Result<Zeroizing<[u8; 32]>, aes_kw::Error> {
+            let kek_cipher = KekAes256::from(*self.subkey(purpose));
+            let mut key = Zeroizing::new([0u8; 32]);
+            kek_cipher.unwrap(&wrapped_key, key.as_mut())?;
+            Ok(key)
+        }
+
+        fn fernet(&self) -> Fernet {
+            let key = self.subkey(Purpose::Encryption);
+            Fernet::new(&Zeroizing::new(base64_url::encode(key.as_ref()))).unwrap()
+        }
+
+        /// Encrypt with the current algorithm.
+        /// `associated_data` is authenticated but not encrypted; decryption only succeeds with
+        /// the same associated data, which binds the ciphertext to where it is stored.
+        pub fn encrypt(&self, data: &[u8], associated_data: &[u8]) -> String {
+            self.encrypt_with(Algorithm::CURRENT, data, associated_data)
+        }
 
-            Ok(Self { fernet })
+        pub fn encrypt_with(
+            &self,
+            algorithm: Algorithm,
+            data: &[u8],
+            associated_data: &[u8],
+        ) -> String {
+            let payload = match algorithm {
+                Algorithm::Fernet => Backend::encrypt(&self.fernet(), data, associated_data),
+                Algorithm::XChaCha20Poly1305 => {
+                    Backend::encrypt(&*self.xchacha, data, associated_data)
+                }
+            };
+            format!("{}:{}", algorithm.tag(), payload)
         }
 
-        pub fn encrypt(&self, data: &[u8]) -> String {
-            self.fernet.encrypt(data)
+        /// Decrypt with whichever algorithm the ciphertext is tagged with.
+        /// Fails if the ciphertext was encrypted with other associated data. Fernet tokens are
+        /// refused, as they would decrypt under any associated data.
+        pub fn decrypt(
+            &self,
+            ciphertext: &str,
+            associated_data: &[u8],
+        ) -> Result<Zeroizing<Vec<u8>>, DecryptionError> {
+            match Algorithm::parse(ciphertext) {
+                Some((Algorithm::Fernet, _)) | None => Err(DecryptionError),
+                Some((Algorithm::XChaCha20Poly1305, payload)) => {
+                    Backend::decrypt(&*self.xchacha, payload, associated_data).map(locked_plaintext)
+                }
+            }
         }
 
-        pub fn decrypt(&self, ciphertext: &str) -> Result<Vec<u8>, DecryptionError> {
-            match self.fernet.decrypt(ciphertext) {
-                Ok(decrypted) => Ok(decrypted),
-                Err(e) => Err(e),
+        /// Decrypt a value written before values were bound to where they are stored,
+        /// with any algorithm and no associated data. Only for migrating such values.
+        pub fn decrypt_unbound(
+            &self,
+            ciphertext: &str,
+        ) -> Result<Zeroizing<Vec<u8>>, DecryptionError> {
+            match Algorithm::parse(ciphertext).ok_or(DecryptionError)? {
+                (Algorithm::Fernet, payload) => Backend::decrypt(&self.fernet(), payload, b""),
+                (Algorithm::XChaCha20Poly1305, payload) => {
+                    Backend::decrypt(&*self.xchacha, payload, b"")
+                }
             }
+            .map(locked_plaintext)
+        }
+
+        /// Encrypt with the current algorithm under the subkey for `purpose` instead of the
+        /// encryption subkey, for data kept apart from the stored fields.
+        pub fn encrypt_for(
+            &self,
+            purpose: Purpose,
+            data: &[u8],
+            associated_data: &[u8],
+        ) -> String {
+            let key = self.subkey(purpose);
+            let backend = XChaCha20Poly1305::new(key.as_ref().into());
+            format!(
+                "{}:{}",
+                Algorithm::CURRENT.tag(),
+                Backend::encrypt(&backend, data, associated_data)
+            )
         }
 
-        pub fn generate_key() -> String {
-            Fernet::generate_key()
+        /// Decrypt what `encrypt_for` encrypted with the same `purpose`.
+        pub fn decrypt_for(
+            &self,
+            purpose: Purpose,
+            ciphertext: &str,
+            associated_data: &[u8],
+        ) -> Result<Zeroizing<Vec<u8>>, DecryptionError> {
+            let Some((Algorithm::XChaCha20Poly1305, payload)) = Algorithm::parse(ciphertext)
+            else {
+                return Err(DecryptionError);
+            };
+            let key = self.subkey(purpose);
+            let backend = XChaCha20Poly1305::new(key.as_ref().into());
+            Backend::decrypt(&backend, payload, associated_data).map(locked_plaintext)
         }
     }
+    /// Decrypted data, with its buffer locked into RAM.
+    fn locked_plaintext(plaintext: Vec<u8>) -> Zeroizing<Vec<u8>> {
+        hardening::lock_memory(plaintext.as_slice());
+        Zeroizing::new(plaintext)
+    }
+
+    fn derive_subkey(key: &[u8; 32], scheme: KeyScheme, purpose: Purpose) -> Zeroizing<[u8; 32]> {
+        let mut subkey = Zeroizing::new([0u8; 32]);
+        match (scheme, purpose) {
+            (KeyScheme::Legacy, Purpose::Encryption) => subkey.copy_from_slice(key),
+            (KeyScheme::Legacy, _) => *subkey = HMAC::mac(purpose.label(), key),
+            (KeyScheme::Hkdf, _) => {
+                let prk = Zeroizing::new(HKDF::extract(b"password-guardian", key));
+                let info = format!("password-guardian v2 {}", purpose.label());
+                HKDF::expand(subkey.as_mut(), prk.as_ref(), info);
+            }
+        }
+        subkey
+    }
+
     #[cfg(test)]
     mod test {
-        use super::Cipher;
+        use super::{Algorithm, Cipher, KeyScheme, Purpose};
+        use hmac_sha256::HMAC;
 
         #[test]
         fn create() {
@@ -55,7 +405,7 @@ pub mod encryption {
                     .unwrap()
                     .try_into()
                     .unwrap();
-            assert!(Cipher::from_unwrap(kek, wrap).is_ok());
+            assert!(Cipher::from_unwrap(&kek, wrap, KeyScheme::CURRENT).is_ok());
         }
 
         #[test]
@@ -69,31 +419,637 @@ pub mod encryption {
                     .unwrap()
                     .try_into()
                     .unwrap();
-            let cipher = Cipher::from_unwrap(kek, wrap).unwrap();
+            let cipher = Cipher::from_unwrap(&kek, wrap, KeyScheme::CURRENT).unwrap();
             let plaintext = "Secrets!";
-            let ciphertext = cipher.encrypt(plaintext.as_bytes());
-            let decrypted = cipher.decrypt(&ciphertext);
+            let ciphertext = cipher.encrypt(plaintext.as_bytes(), b"");
+            let decrypted = cipher.decrypt(&ciphertext, b"");
             assert!(decrypted.is_ok());
-            assert_eq!(decrypted.unwrap(), plaintext.as_bytes());
+            assert_eq!(*decrypted.unwrap(), plaintext.as_bytes());
+        }
+
+        #[test]
+        fn rewrap() {
+            let kek: [u8; 32] = base64_url::decode("QR66Cx_3lGU-R3TMWEivbx8I00qXgdHMdJxer92LSo8")
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let wrap: [u8; 40] =
+                base64_url::decode("3PV8v4uITiZ9scpB7usBBoFPClGoH5XnMQSeneM3_Z3FfbZ6PzE1ag")
+                    .unwrap()
+                    .try_into()
+                    .unwrap();
+            let cipher = Cipher::from_unwrap(&kek, wrap, KeyScheme::CURRENT).unwrap();
+            let ciphertext = cipher.encrypt(b"Secrets!", b"");
+
+            let new_kek = [7u8; 32];
+            let new_wrap = cipher.wrap(&new_kek);
+            assert!(Cipher::from_unwrap(&kek, new_wrap, KeyScheme::CURRENT).is_err());
+            let rewrapped = Cipher::from_unwrap(&new_kek, new_wrap, KeyScheme::CURRENT).unwrap();
+            assert_eq!(*rewrapped.decrypt(&ciphertext, b"").unwrap(), b"Secrets!");
+        }
+
+        #[test]
+        fn algorithms() {
+            let cipher = Cipher::new(&[1u8; 32]);
+            let ciphertext = cipher.encrypt(b"Secrets!", b"");
+            assert!(ciphertext.starts_with("xchacha20poly1305:"));
+
+            let fernet = cipher.encrypt_with(Algorithm::Fernet, b"Secrets!", b"");
+            assert_eq!(*cipher.decrypt_unbound(&fernet).unwrap(), b"Secrets!");
+
+            // Legacy Fernet tokens are stored without a tag
+            let legacy = fernet.strip_prefix("fernet:").unwrap();
+            assert_eq!(*cipher.decrypt_unbound(legacy).unwrap(), b"Secrets!");
+
+            // Fernet ignores associated data, so it is never accepted where it is expected
+            assert!(cipher.decrypt(&fernet, b"").is_err());
+            assert!(cipher.decrypt(legacy, b"item:password").is_err());
+
+            assert!(cipher.decrypt("rot13:Frpergf!", b"").is_err());
+            assert!(cipher.decrypt_unbound("rot13:Frpergf!").is_err());
+        }
+
+        #[test]
+        fn tampered() {
+            let cipher = Cipher::new(&[1u8; 32]);
+            let ciphertext = cipher.encrypt(b"Secrets!", b"");
+            let (tag, payload) = ciphertext.split_once(':').unwrap();
+            let mut payload = base64_url::decode(payload).unwrap();
+            *payload.last_mut().unwrap() ^= 1;
+            let tampered = format!("{tag}:{}", base64_url::encode(&payload));
+            assert!(cipher.decrypt(&tampered, b"").is_err());
+            assert!(Cipher::new(&[2u8; 32]).decrypt(&ciphertext, b"").is_err());
+        }
+
+        #[test]
+        fn subkeys() {
+            let cipher = Cipher::new(&[1u8; 32]);
+            let purposes = [
+                Purpose::Encryption,
+                Purpose::Integrity,
+                Purpose::Metadata,
+                Purpose::Database,
+                Purpose::BlindIndex,
+                Purpose::Export,
+            ];
+            let subkeys: Vec<_> = purposes.iter().map(|p| *cipher.subkey(*p)).collect();
+            for (i, subkey) in subkeys.iter().enumerate() {
+                assert_ne!(*subkey, [1u8; 32]);
+                assert!(!subkeys[..i].contains(subkey));
+            }
+
+            // Vaults created before the key hierarchy keep their keys
+            let legacy = cipher.rederive(KeyScheme::Legacy);
+            assert_eq!(*legacy.subkey(Purpose::Encryption), [1u8; 32]);
+            assert_eq!(
+                *legacy.subkey(Purpose::Integrity),
+                HMAC::mac(b"integrity", [1u8; 32])
+            );
+            assert_ne!(
+                *legacy.subkey(Purpose::Metadata),
+                *cipher.subkey(Purpose::Metadata)
+            );
+
+            let ciphertext = legacy.encrypt(b"Secrets!", b"");
+            assert!(cipher.decrypt(&ciphertext, b"").is_err());
+            let upgraded = legacy.rederive(KeyScheme::CURRENT);
+            assert!(upgraded.decrypt(&ciphertext, b"").is_err());
+            assert_eq!(*legacy.decrypt(&ciphertext, b"").unwrap(), b"Secrets!");
+
+            let wrap = cipher.wrap_key(Purpose::Database, &[9u8; 32]);
+            assert_eq!(
+                *cipher.unwrap_key(Purpose::Database, wrap).unwrap(),
+                [9u8; 32]
+            );
+            assert!(cipher.unwrap_key(Purpose::Export, wrap).is_err());
+            assert!(legacy.unwrap_key(Purpose::Database, wrap).is_err());
+        }
+
+        #[test]
+        fn associated_data() {
+            let cipher = Cipher::new(&[1u8; 32]);
+            let ciphertext = cipher.encrypt(b"Secrets!", b"github:password");
+            assert_eq!(
+                *cipher.decrypt(&ciphertext, b"github:password").unwrap(),
+                b"Secrets!"
+            );
+            assert!(cipher.decrypt(&ciphertext, b"bank:password").is_err());
+            assert!(cipher.decrypt(&ciphertext, b"github:username").is_err());
         }
     }
 }
 
 pub mod kdf {
     use argon2::{self, Config, Variant, Version};
-    pub fn derive_kek(plaintext: &str, salt: &[u8]) -> [u8; 32] {
-        const ARGON2_CONFIG: Config<'_> = Config {
-            ad: &[],
-            hash_length: 32,
-            lanes: 4,
-            mem_cost: 65536,
-            secret: &[],
-            time_cost: 8,
-            variant: Variant::Argon2i,
-            version: Version::Version13,
-        };
-        let derived_key = argon2::hash_raw(plaintext.as_bytes(), salt, &ARGON2_CONFIG).unwrap();
+    use getrandom::getrandom;
+    use hmac_sha256::Hash;
+    use serde::{Deserialize, Serialize};
+    use std::fmt::Display;
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use zeroize::Zeroizing;
+
+    use crate::components::hardening::{self, Locked};
+
+    /// Argon2 parameters used to derive the KEK, stored in the metadata file.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct KdfParams {
+        pub variant: String,
+        pub mem_cost: u32,
+        pub time_cost: u32,
+        pub lanes: u32,
+        pub version: u32,
+    }
+
+    impl KdfParams {
+        /// Parameters of vaults created before they were recorded in the metadata.
+        pub fn legacy() -> Self {
+            Self {
+                variant: Variant::Argon2i.as_lowercase_str().into(),
+                mem_cost: 65536,
+                time_cost: 8,
+                lanes: 4,
+                version: Version::Version13.as_u32(),
+            }
+        }
+
+        /// Argon2id parameters with the given memory (KiB) and time costs.
+        pub fn argon2id(mem_cost: u32, time_cost: u32) -> Self {
+            Self {
+                variant: Variant::Argon2id.as_lowercase_str().into(),
+                mem_cost,
+                time_cost,
+                lanes: 4,
+                version: Version::Version13.as_u32(),
+            }
+        }
+
+        /// Whether either cost is lower than in `other`, making the KEK cheaper to brute-force.
+        pub fn is_weaker_than(&self, other: &Self) -> bool {
+            self.mem_cost < other.mem_cost || self.time_cost < other.time_cost
+        }
+
+        fn config<'a>(&self, secret: &'a [u8]) -> Result<Config<'a>, argon2::Error> {
+            Ok(Config {
+                ad: &[],
+                hash_length: 32,
+                lanes: self.lanes,
+                mem_cost: self.mem_cost,
+                secret,
+                time_cost: self.time_cost,
+                variant: Variant::from_str(&self.variant)?,
+                version: Version::from_u32(self.version)?,
+            })
+        }
+    }
+
+    impl Default for KdfParams {
+        fn default() -> Self {
+            Self::argon2id(65536, 3)
+        }
+    }
+
+    impl Display for KdfParams {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{} (version {:#x}), {} MiB memory, {} iterations, {} lanes",
+                self.variant,
+                self.version,
+                self.mem_cost / 1024,
+                self.time_cost,
+                self.lanes
+            )
+        }
+    }
+
+    /// Derive the KEK from the master password.
+    /// The keyfile hash, if any, is the Argon2 secret, so the password alone is not enough.
+    pub fn derive_kek(
+        plaintext: &str,
+        salt: &[u8],
+        params: &KdfParams,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<Locked<[u8; 32]>, argon2::Error> {
+        let secret = keyfile.map_or(&[][..], |hash| &hash[..]);
+        let derived_key = Zeroizing::new(argon2::hash_raw(
+            plaintext.as_bytes(),
+            salt,
+            &params.config(secret)?,
+        )?);
+        hardening::lock_memory(derived_key.as_slice());
         assert_eq!(derived_key.len(), 32);
-        derived_key.try_into().unwrap()
+        let mut kek = Locked::new([0u8; 32]);
+        kek.copy_from_slice(&derived_key);
+        Ok(kek)
+    }
+
+    /// Pick Argon2id costs so that deriving the KEK takes about `target` on this machine.
+    /// Memory (KiB) is doubled up to `max_mem_cost` first, then iterations make up the rest.
+    pub fn calibrate(target: Duration, max_mem_cost: u32) -> KdfParams {
+        const MIN_MEM_COST: u32 = 8 * 1024;
+        let measure = |params: &KdfParams| {
+            let start = Instant::now();
+            derive_kek("calibration", &[0u8; 16], params, None).unwrap();
+            start.elapsed().max(Duration::from_millis(1))
+        };
+
+        let max_mem_cost = max_mem_cost.max(1024);
+        let mut params = KdfParams::argon2id(MIN_MEM_COST.min(max_mem_cost), 1);
+        let mut elapsed = measure(&params);
+        while elapsed * 2 <= target && params.mem_cost * 2 <= max_mem_cost {
+            params.mem_cost *= 2;
+            elapsed = measure(&params);
+        }
+        params.time_cost = (target.as_secs_f64() / elapsed.as_secs_f64())
+            .round()
+            .max(1.0) as u32;
+        params
+    }
+
+    /// SHA-256 of the keyfile content. Any file can serve as a keyfile.
+    pub fn hash_keyfile(path: &Path) -> io::Result<Zeroizing<[u8; 32]>> {
+        let mut file = File::open(path)?;
+        let mut hash = Hash::new();
+        let mut buffer = Zeroizing::new([0u8; 4096]);
+        loop {
+            let read = file.read(buffer.as_mut())?;
+            if read == 0 {
+                break;
+            }
+            hash.update(&buffer[..read]);
+        }
+        Ok(Zeroizing::new(hash.finalize()))
+    }
+
+    /// Write a new keyfile of random bytes. Fails if `path` already exists.
+    pub fn generate_keyfile(path: &Path) -> io::Result<()> {
+        let mut content = Zeroizing::new([0u8; 64]);
+        getrandom(content.as_mut()).unwrap();
+        let mut file = File::options().write(true).create_new(true).open(path)?;
+        file.write_all(content.as_ref())?;
+        file.sync_all()
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::{calibrate, derive_kek, KdfParams};
+        use std::time::Duration;
+
+        #[test]
+        fn parameters_change_kek() {
+            let salt = [1u8; 16];
+            let weak = KdfParams::argon2id(64, 1);
+            let stronger = KdfParams::argon2id(128, 2);
+            let kek = derive_kek("password", &salt, &weak, None).unwrap();
+            assert_eq!(*kek, *derive_kek("password", &salt, &weak, None).unwrap());
+            assert_ne!(*kek, *derive_kek("password", &salt, &stronger, None).unwrap());
+        }
+
+        #[test]
+        fn keyfile_changes_kek() {
+            let salt = [1u8; 16];
+            let params = KdfParams::argon2id(64, 1);
+            let kek = derive_kek("password", &salt, &params, None).unwrap();
+            let with_keyfile = derive_kek("password", &salt, &params, Some(&[1u8; 32])).unwrap();
+            assert_ne!(*kek, *with_keyfile);
+            assert_ne!(
+                *with_keyfile,
+                *derive_kek("password", &salt, &params, Some(&[2u8; 32])).unwrap()
+            );
+        }
+
+        #[test]
+        fn calibration_respects_memory_ceiling() {
+            let params = calibrate(Duration::from_millis(200), 16 * 1024);
+            assert_eq!(params.variant, "argon2id");
+            assert!(params.mem_cost <= 16 * 1024);
+            assert!(params.time_cost >= 1);
+        }
+
+        #[test]
+        fn downgrade_is_recognised() {
+            let current = KdfParams::argon2id(65536, 3);
+            assert!(!KdfParams::argon2id(65536, 3).is_weaker_than(&current));
+            assert!(!KdfParams::argon2id(131072, 4).is_weaker_than(&current));
+            assert!(KdfParams::argon2id(32768, 4).is_weaker_than(&current));
+            assert!(KdfParams::argon2id(131072, 2).is_weaker_than(&current));
+        }
+
+        #[test]
+        fn invalid_parameters() {
+            let mut params = KdfParams::argon2id(64, 1);
+            params.variant = "scrypt".into();
+            assert!(derive_kek("password", &[1u8; 16], &params, None).is_err());
+        }
+    }
+}
+
+pub mod recovery {
+    use getrandom::getrandom;
+    use hmac_sha256::Hash;
+    use std::fmt::Display;
+    use zeroize::Zeroizing;
+
+    /// Crockford's base32 alphabet, without the easily confused I, L, O and U.
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    /// High-entropy key unlocking the vault when the master password is forgotten.
+    /// 128 random bits followed by a 32-bit checksum, written as 8 groups of 4 base32 digits.
+    pub struct RecoveryKey {
+        bytes: Zeroizing<[u8; 20]>,
+    }
+
+    impl RecoveryKey {
+        pub fn generate() -> Self {
+            let mut bytes = Zeroizing::new([0u8; 20]);
+            getrandom(&mut bytes[..16]).unwrap();
+            let checksum = Hash::hash(&bytes[..16]);
+            bytes[16..].copy_from_slice(&checksum[..4]);
+            Self { bytes }
+        }
+
+        /// Read a recovery key as typed by the user.
+        /// Case, dashes and spaces are ignored, I and L are read as 1 and O as 0.
+        /// Returns `None` if it is malformed or its checksum does not match.
+        pub fn parse(text: &str) -> Option<Self> {
+            Self::from_bytes(&decode(text, 20)?)
+        }
+
+        fn from_bytes(bytes: &[u8]) -> Option<Self> {
+            if bytes.len() != 20 || bytes[16..] != Hash::hash(&bytes[..16])[..4] {
+                return None;
+            }
+            let mut key = Zeroizing::new([0u8; 20]);
+            key.copy_from_slice(bytes);
+            Some(Self { bytes: key })
+        }
+
+        /// Ungrouped form, used as the password of the recovery key slot.
+        pub fn canonical(&self) -> Zeroizing<String> {
+            encode(self.bytes.as_ref())
+        }
+
+        /// Split the key into `count` shares, any `threshold` of which recover it.
+        pub fn split(&self, threshold: u8, count: u8) -> Vec<RecoveryShare> {
+            shamir::split(self.bytes.as_ref(), threshold, count)
+                .into_iter()
+                .map(|(x, y)| {
+                    let mut bytes = Zeroizing::new([0u8; 22]);
+                    bytes[0] = threshold;
+                    bytes[1] = x;
+                    bytes[2..].copy_from_slice(&y);
+                    RecoveryShare { bytes }
+                })
+                .collect()
+        }
+
+        /// Recover the key from at least as many distinct shares as their threshold.
+        /// Returns `None` if there are too few shares or they come from different splits.
+        pub fn combine(shares: &[RecoveryShare]) -> Option<Self> {
+            let threshold = shares.first()?.threshold();
+            let mut points: Vec<(u8, &[u8])> = Vec::new();
+            for share in shares {
+                if share.threshold() != threshold {
+                    return None;
+                }
+                if points.iter().all(|(x, _)| *x != share.bytes[1]) {
+                    points.push((share.bytes[1], &share.bytes[2..]));
+                }
+            }
+            if points.len() < threshold as usize {
+                return None;
+            }
+            Self::from_bytes(&shamir::combine(&points[..threshold as usize]))
+        }
+    }
+
+    impl Display for RecoveryKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write_grouped(f, &self.canonical())
+        }
+    }
+
+    /// One share of a split recovery key: threshold, x coordinate and the 20 bytes of the key
+    /// evaluated at x, followed by a 24-bit checksum, written as 10 groups of 4 base32 digits.
+    pub struct RecoveryShare {
+        bytes: Zeroizing<[u8; 22]>,
+    }
+
+    impl RecoveryShare {
+        /// Read a share as typed by the user, see `RecoveryKey::parse`.
+        pub fn parse(text: &str) -> Option<Self> {
+            let decoded = decode(text, 25)?;
+            let checksum = Hash::hash(&decoded[..22]);
+            if decoded[22..] != checksum[..3] || decoded[0] < 2 || decoded[1] == 0 {
+                return None;
+            }
+            let mut bytes = Zeroizing::new([0u8; 22]);
+            bytes.copy_from_slice(&decoded[..22]);
+            Some(Self { bytes })
+        }
+
+        /// Number of shares needed to recover the key.
+        pub fn threshold(&self) -> u8 {
+            self.bytes[0]
+        }
+
+        /// x coordinate, telling the shares of one split apart.
+        pub fn x(&self) -> u8 {
+            self.bytes[1]
+        }
+    }
+
+    impl Display for RecoveryShare {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let mut bytes = Zeroizing::new([0u8; 25]);
+            bytes[..22].copy_from_slice(self.bytes.as_ref());
+            bytes[22..].copy_from_slice(&Hash::hash(self.bytes.as_ref())[..3]);
+            write_grouped(f, &encode(bytes.as_ref()))
+        }
+    }
+
+    /// Base32 digits of `bytes`, whose length is a multiple of 5.
+    fn encode(bytes: &[u8]) -> Zeroizing<String> {
+        let digits = bytes.len() * 8 / 5;
+        let mut text = Zeroizing::new(String::with_capacity(digits));
+        for digit in 0..digits {
+            let mut value = 0;
+            for bit in 0..5 {
+                let position = digit * 5 + bit;
+                value = value << 1 | (bytes[position / 8] >> (7 - position % 8)) & 1;
+            }
+            text.push(ALPHABET[value as usize] as char);
+        }
+        text
+    }
+
+    /// Read `len` bytes from base32 digits, ignoring case, dashes and spaces.
+    fn decode(text: &str, len: usize) -> Option<Zeroizing<Vec<u8>>> {
+        let mut bytes = Zeroizing::new(vec![0u8; len]);
+        let mut digits = 0;
+        for c in text.chars().filter(|c| !matches!(c, '-' | ' ')) {
+            let c = match c.to_ascii_uppercase() {
+                'I' | 'L' => '1',
+                'O' => '0',
+                c => c,
+            };
+            let value = ALPHABET.iter().position(|&a| a as char == c)?;
+            if digits == len * 8 / 5 {
+                return None;
+            }
+            // Digit n holds bits 5n..5n+5, most significant first.
+            for bit in 0..5 {
+                if value & (0b10000 >> bit) != 0 {
+                    let position = digits * 5 + bit;
+                    bytes[position / 8] |= 0x80 >> (position % 8);
+                }
+            }
+            digits += 1;
+        }
+        (digits == len * 8 / 5).then_some(bytes)
+    }
+
+    /// Write base32 digits in groups of 4 separated by dashes.
+    fn write_grouped(f: &mut std::fmt::Formatter<'_>, digits: &str) -> std::fmt::Result {
+        for (index, group) in digits.as_bytes().chunks(4).enumerate() {
+            if index > 0 {
+                write!(f, "-")?;
+            }
+            write!(f, "{}", std::str::from_utf8(group).unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Shamir's secret sharing over GF(2^8), byte by byte.
+    mod shamir {
+        use getrandom::getrandom;
+        use zeroize::Zeroizing;
+
+        /// Multiplication modulo the AES polynomial `x^8 + x^4 + x^3 + x + 1`, without branches.
+        fn mul(mut a: u8, mut b: u8) -> u8 {
+            let mut product = 0;
+            for _ in 0..8 {
+                product ^= (b & 1).wrapping_neg() & a;
+                a = (a << 1) ^ ((a >> 7).wrapping_neg() & 0x1b);
+                b >>= 1;
+            }
+            product
+        }
+
+        /// `a^254 = a^-1` for non-zero `a`.
+        fn inv(a: u8) -> u8 {
+            let mut result = 1;
+            let mut power = a;
+            let mut exponent = 254u8;
+            while exponent > 0 {
+                if exponent & 1 == 1 {
+                    result = mul(result, power);
+                }
+                power = mul(power, power);
+                exponent >>= 1;
+            }
+            result
+        }
+
+        /// Points `(x, f(x))` for x in `1..=count` of random polynomials of degree
+        /// `threshold - 1` whose constant terms are the bytes of `secret`.
+        pub fn split(secret: &[u8], threshold: u8, count: u8) -> Vec<(u8, Zeroizing<Vec<u8>>)> {
+            assert!(2 <= threshold && threshold <= count);
+            let mut coefficients =
+                Zeroizing::new(vec![0u8; secret.len() * (threshold as usize - 1)]);
+            getrandom(&mut coefficients).unwrap();
+
+            (1..=count)
+                .map(|x| {
+                    let y = secret
+                        .iter()
+                        .zip(coefficients.chunks(threshold as usize - 1))
+                        .map(|(constant, coefficients)| {
+                            // Horner's method, from the highest degree down to the constant.
+                            coefficients
+                                .iter()
+                                .rev()
+                                .chain([constant])
+                                .fold(0, |acc, coefficient| mul(acc, x) ^ coefficient)
+                        })
+                        .collect();
+                    (x, Zeroizing::new(y))
+                })
+                .collect()
+        }
+
+        /// Lagrange interpolation of the points at x = 0.
+        pub fn combine(points: &[(u8, &[u8])]) -> Zeroizing<Vec<u8>> {
+            let len = points[0].1.len();
+            let mut secret = Zeroizing::new(vec![0u8; len]);
+            for (i, (xi, yi)) in points.iter().enumerate() {
+                // l_i(0) = prod_{j != i} x_j / (x_j - x_i), subtraction being xor.
+                let mut basis = 1;
+                for (j, (xj, _)) in points.iter().enumerate() {
+                    if i != j {
+                        basis = mul(basis, mul(*xj, inv(xj ^ xi)));
+                    }
+                }
+                for (byte, y) in secret.iter_mut().zip(yi.iter()) {
+                    *byte ^= mul(basis, *y);
+                }
+            }
+            secret
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::{RecoveryKey, RecoveryShare};
+
+        #[test]
+        fn round_trip() {
+            let key = RecoveryKey::generate();
+            let shown = key.to_string();
+            assert_eq!(shown.len(), 39);
+            let parsed = RecoveryKey::parse(&shown.to_lowercase().replace('-', " ")).unwrap();
+            assert_eq!(parsed.canonical(), key.canonical());
+        }
+
+        #[test]
+        fn typos_are_detected() {
+            let key = RecoveryKey::generate().canonical();
+            let mut typo = key.to_string();
+            let replacement = if typo.starts_with('A') { "B" } else { "A" };
+            typo.replace_range(0..1, replacement);
+            assert!(RecoveryKey::parse(&typo).is_none());
+            assert!(RecoveryKey::parse(&key[..31]).is_none());
+            assert!(RecoveryKey::parse(&format!("{}0", *key)).is_none());
+            assert!(RecoveryKey::parse("master password").is_none());
+        }
+
+        #[test]
+        fn any_threshold_shares_recover() {
+            let key = RecoveryKey::generate();
+            let shares: Vec<String> = key.split(3, 5).iter().map(|s| s.to_string()).collect();
+            let parse = |indices: &[usize]| -> Vec<RecoveryShare> {
+                indices
+                    .iter()
+                    .map(|i| RecoveryShare::parse(&shares[*i]).unwrap())
+                    .collect()
+            };
+
+            for indices in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+                let recovered = RecoveryKey::combine(&parse(&indices)).unwrap();
+                assert_eq!(recovered.canonical(), key.canonical());
+            }
+            assert!(RecoveryKey::combine(&parse(&[0, 1])).is_none());
+            assert!(RecoveryKey::combine(&parse(&[0, 1, 1])).is_none());
+            assert_eq!(parse(&[1])[0].x(), parse(&[1])[0].x());
+            assert_ne!(parse(&[1])[0].x(), parse(&[2])[0].x());
+
+            let other = RecoveryKey::generate().split(3, 5);
+            let mut mixed = parse(&[0, 1]);
+            mixed.extend(other.into_iter().skip(2).take(1));
+            assert!(RecoveryKey::combine(&mixed).is_none());
+        }
     }
 }
@@ -1,18 +1,50 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use rusqlite::Connection;
+use chrono::prelude::*;
+use rusqlite::types::Type;
+use rusqlite::{Connection, OptionalExtension};
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
-use super::logindata::LoginData;
+use super::cryptography::encryption::Cipher;
 use super::error::DataStorageError;
+use super::history;
+use super::logindata::LoginData;
 
-
-pub fn database_connection(path: &Path) -> Result<Connection, DataStorageError> {
+/// Open the database file. With `key`, the whole file is page-encrypted by SQLCipher,
+/// which is applied before anything is read.
+/// Pending schema migrations are applied right away, see `migrate_schema`.
+pub fn database_connection(
+    path: &Path,
+    key: Option<&[u8; 32]>,
+) -> Result<Connection, DataStorageError> {
     if !path.exists() {
         return Err(DataStorageError::FileNotFound {
             path: path.to_path_buf(),
         });
     }
-    Ok(Connection::open(path)?)
+    let connection = Connection::open(path)?;
+    if let Some(key) = key {
+        apply_key(&connection, key)?;
+    }
+    migrate_schema(&connection, path)?;
+    Ok(connection)
+}
+
+/// Key the connection with a raw 256-bit key, skipping SQLCipher's own key derivation,
+/// and check that it decrypts the file.
+fn apply_key(connection: &Connection, key: &[u8; 32]) -> Result<(), DataStorageError> {
+    // Plain SQLite silently ignores `PRAGMA key` and would write the file in clear.
+    let cipher_version: Option<String> = connection
+        .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+        .optional()?;
+    if cipher_version.is_none() {
+        return Err(DataStorageError::PageEncryptionUnsupported);
+    }
+    let hex: Zeroizing<String> = Zeroizing::new(key.iter().map(|b| format!("{b:02x}")).collect());
+    connection.execute_batch(&Zeroizing::new(format!("PRAGMA key = \"x'{}'\";", *hex)))?;
+    connection.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))?;
+    Ok(())
 }
 
 pub fn create_table(connection: &Connection) -> Result<(), DataStorageError> {
@@ -24,37 +56,377 @@ pub fn create_table(connection: &Connection) -> Result<(), DataStorageError> {
             name TEXT NOT NULL,
             username TEXT NOT NULL,
             date_modified TEXT NOT NULL,
-            password TEXT NOT NULL
+            password TEXT NOT NULL,
+            uuid TEXT NOT NULL UNIQUE,
+            date_deleted TEXT
         )",
             (),
         )
         .map_err(|e| DataStorageError::DatabaseError { cause: e })?;
+    history::create_table(connection)?;
+    connection.pragma_update(None, "user_version", DATA_VERSION)?;
+    Ok(())
+}
+
+/// `user_version` of the current data format.
+/// 0. Name, username and date are stored in clear text.
+/// 1. Every field is encrypted.
+/// 2. Every login has a UUID, encrypted fields are bound to it and their field name.
+/// 3. Passwords replaced by an update are kept in `PasswordHistory`.
+/// 4. Deleted logins stay in the trash, with their encrypted deletion date, until purged.
+const DATA_VERSION: i32 = 4;
+
+/// A step bringing the database from the previous version to the next one.
+enum Migration {
+    /// Structural change, applied as soon as the database is opened.
+    Schema(fn(&Connection) -> Result<(), DataStorageError>),
+    /// Change of the stored logins, applied after unlock once the database has been verified.
+    /// It is given the version the database is migrated from.
+    Data(fn(&Connection, &Cipher, i32) -> Result<(), DataStorageError>),
+}
+
+/// `(version, step)` in ascending order, the last one being `DATA_VERSION`.
+/// `create_table` creates the latest format directly, so a new step goes in both places.
+const MIGRATIONS: &[(i32, Migration)] = &[
+    (2, Migration::Data(bind_to_uuid)),
+    (3, Migration::Schema(history::create_table)),
+    (4, Migration::Schema(add_trash)),
+];
+
+fn version(connection: &Connection) -> Result<i32, DataStorageError> {
+    Ok(connection.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Apply the steps after the current version, stopping before the first step that needs a
+/// cipher when there is none.
+fn run_migrations(
+    connection: &Connection,
+    cipher: Option<&Cipher>,
+) -> Result<(), DataStorageError> {
+    for (version, migration) in MIGRATIONS {
+        let current = self::version(connection)?;
+        if current >= *version {
+            continue;
+        }
+        match (migration, cipher) {
+            (Migration::Schema(step), _) => step(connection)?,
+            (Migration::Data(step), Some(cipher)) => step(connection, cipher, current)?,
+            (Migration::Data(_), None) => break,
+        }
+        connection.pragma_update(None, "user_version", version)?;
+    }
+    Ok(())
+}
+
+/// Refuse databases written by a newer version and apply pending schema migrations in a
+/// single transaction. The file is first copied to `backup_path`, so it can be restored if the
+/// migration, or a later data migration, goes wrong, until `remove_backups` deletes it.
+/// A database without tables is left as it is for `create_table`.
+fn migrate_schema(connection: &Connection, path: &Path) -> Result<(), DataStorageError> {
+    let version = version(connection)?;
+    if version > DATA_VERSION {
+        return Err(DataStorageError::UnsupportedDataVersion { version });
+    }
+    let is_new = connection
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'Accounts'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_none();
+    if version == DATA_VERSION || is_new {
+        return Ok(());
+    }
+
+    std::fs::copy(path, backup_path(path, version))?;
+
+    let transaction = connection.unchecked_transaction()?;
+    run_migrations(&transaction, None)?;
+    transaction.commit()?;
     Ok(())
 }
 
-pub fn insert_login(connection: &Connection, login: LoginData) {
+/// `<file>.v<version>.bak`, the copy of the database taken before migrating from `version`.
+fn backup_path(path: &Path, version: i32) -> PathBuf {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(format!(".v{version}.bak"));
+    backup_path.into()
+}
+
+/// Delete the copies taken before migrations, once the migrated database has been committed.
+/// They hold every row as it was, deleted ones included, in a format that is no longer
+/// upgraded, so they must not outlive the migration. Returns the number of copies deleted.
+pub fn remove_backups(path: &Path) -> Result<usize, DataStorageError> {
+    let mut removed = 0;
+    for version in 0..DATA_VERSION {
+        match std::fs::remove_file(backup_path(path, version)) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(removed)
+}
+
+/// Apply the remaining migrations, data migrations included.
+/// Run it inside a transaction.
+pub fn migrate(connection: &Connection, cipher: &Cipher) -> Result<(), DataStorageError> {
+    run_migrations(connection, Some(cipher))
+}
+
+/// Version 2: give every login a UUID and bind its encrypted fields to it.
+fn bind_to_uuid(
+    connection: &Connection,
+    cipher: &Cipher,
+    version: i32,
+) -> Result<(), DataStorageError> {
+    connection.execute("ALTER TABLE Accounts ADD COLUMN uuid TEXT", ())?;
+    let mut sql =
+        connection.prepare("SELECT id, name, username, date_modified, password FROM Accounts")?;
+    let rows = sql
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                [row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?],
+            ))
+        })?
+        .collect::<Result<Vec<(usize, [String; 4])>, _>>()?;
+
+    for (id, [name, username, date_modified, password]) in rows {
+        let uuid = Uuid::new_v4();
+        // Fields were encrypted without associated data, if at all.
+        let decrypt = |value: String| {
+            if version == 0 {
+                return Ok(value);
+            }
+            cipher
+                .decrypt_unbound(&value)
+                .ok()
+                .and_then(|decrypted| std::str::from_utf8(&decrypted).map(str::to_owned).ok())
+                .ok_or(DataStorageError::DecryptionFailed { uuid })
+        };
+        let mut login = LoginData {
+            uuid,
+            name: decrypt(name)?,
+            username: decrypt(username)?,
+            date_modified: decrypt(date_modified)?,
+            password: String::new(),
+            date_deleted: None,
+        };
+        let password = cipher
+            .decrypt_unbound(&password)
+            .map_err(|_| DataStorageError::DecryptionFailed { uuid })?;
+        login.set_password(cipher, &password);
+
+        // Written out rather than with `update_login`, which knows columns of later versions.
+        connection.execute(
+            "UPDATE Accounts SET uuid = ?1, name = ?2, username = ?3, date_modified = ?4, password = ?5 WHERE id = ?6",
+            (
+                login.uuid.to_string(),
+                cipher.encrypt(login.name.as_bytes(), &login.associated_data("name")),
+                cipher.encrypt(login.username.as_bytes(), &login.associated_data("username")),
+                cipher.encrypt(
+                    login.date_modified.as_bytes(),
+                    &login.associated_data("date_modified"),
+                ),
+                &login.password,
+                id,
+            ),
+        )?;
+    }
     connection.execute(
-        "INSERT INTO Accounts (name, username, date_modified, password) VALUES (?1, ?2, ?3, ?4)",
-        [&login.name, &login.username, &login.date_modified, &login.password]
-    ).unwrap();
+        "CREATE UNIQUE INDEX IF NOT EXISTS AccountsUuid ON Accounts (uuid)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Version 4: keep deleted logins in the trash.
+fn add_trash(connection: &Connection) -> Result<(), DataStorageError> {
+    connection.execute("ALTER TABLE Accounts ADD COLUMN date_deleted TEXT", ())?;
+    Ok(())
+}
+
+/// Re-encrypt every login and retired password from `old_cipher` to `new_cipher`.
+/// Run it inside a transaction.
+pub fn reencrypt(
+    connection: &Connection,
+    old_cipher: &Cipher,
+    new_cipher: &Cipher,
+) -> Result<(), DataStorageError> {
+    for mut login in retrieve_all(connection, old_cipher)? {
+        let password = login.decrypt_password(old_cipher)?;
+        login.set_password(new_cipher, &password);
+        update_login(connection, new_cipher, &login)?;
+    }
+    history::reencrypt(connection, old_cipher, new_cipher)
 }
 
-pub fn delete_login(connection: &Connection, id: usize) -> Result<(), DataStorageError> {
-    connection.execute("DELETE FROM Accounts WHERE ID = ?1", [id])?;
-    connection.execute("UPDATE Accounts SET Id = Id - 1 WHERE Id > ?1", [id])?;
+pub fn insert_login(connection: &Connection, cipher: &Cipher, login: LoginData) {
+    connection
+        .execute(
+            "INSERT INTO Accounts (uuid, name, username, date_modified, password) VALUES (?1, ?2, ?3, ?4, ?5)",
+            [
+                &login.uuid.to_string(),
+                &cipher.encrypt(login.name.as_bytes(), &login.associated_data("name")),
+                &cipher.encrypt(login.username.as_bytes(), &login.associated_data("username")),
+                &cipher.encrypt(
+                    login.date_modified.as_bytes(),
+                    &login.associated_data("date_modified"),
+                ),
+                &login.password,
+            ],
+        )
+        .unwrap();
+}
+
+/// Overwrite the stored login with the same UUID, encrypting its fields with `cipher`.
+pub fn update_login(
+    connection: &Connection,
+    cipher: &Cipher,
+    login: &LoginData,
+) -> Result<(), DataStorageError> {
+    connection.execute(
+        "UPDATE Accounts SET name = ?1, username = ?2, date_modified = ?3, password = ?4, date_deleted = ?5 WHERE uuid = ?6",
+        (
+            cipher.encrypt(login.name.as_bytes(), &login.associated_data("name")),
+            cipher.encrypt(login.username.as_bytes(), &login.associated_data("username")),
+            cipher.encrypt(
+                login.date_modified.as_bytes(),
+                &login.associated_data("date_modified"),
+            ),
+            &login.password,
+            login
+                .date_deleted
+                .as_ref()
+                .map(|date| cipher.encrypt(date.as_bytes(), &login.associated_data("date_deleted"))),
+            login.uuid.to_string(),
+        ),
+    )?;
     Ok(())
 }
 
-pub fn retrieve_all(connection: &Connection) -> Result<Vec<LoginData>, DataStorageError> {
-    let mut sql = connection.prepare("SELECT * FROM Accounts").unwrap();
+/// Delete the login with `uuid` and its password history for good. Other logins keep their
+/// identity.
+pub fn delete_login(connection: &Connection, uuid: &Uuid) -> Result<(), DataStorageError> {
+    connection.execute("DELETE FROM Accounts WHERE uuid = ?1", [uuid.to_string()])?;
+    history::delete_all(connection, uuid)
+}
+
+/// Delete the logins in the trash for which `is_expired` holds on their deletion date.
+/// Returns the number of logins deleted.
+fn delete_trashed(
+    connection: &Connection,
+    cipher: &Cipher,
+    is_expired: impl Fn(&str) -> bool,
+) -> Result<usize, DataStorageError> {
+    let mut deleted = 0;
+    for login in retrieve_all(connection, cipher)? {
+        if login.date_deleted.as_deref().is_some_and(&is_expired) {
+            delete_login(connection, &login.uuid)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Delete the logins that have been in the trash for more than `days` days, none if `days`
+/// is 0. Returns the number of logins deleted.
+pub fn purge_trash(
+    connection: &Connection,
+    cipher: &Cipher,
+    days: u32,
+) -> Result<usize, DataStorageError> {
+    if days == 0 {
+        return Ok(0);
+    }
+    let limit = Utc::now() - chrono::Duration::days(days.into());
+    delete_trashed(connection, cipher, |date| {
+        DateTime::parse_from_rfc2822(date).is_ok_and(|date| date < limit)
+    })
+}
+
+/// Delete every login in the trash. Returns the number of logins deleted.
+pub fn empty_trash(connection: &Connection, cipher: &Cipher) -> Result<usize, DataStorageError> {
+    delete_trashed(connection, cipher, |_| true)
+}
+
+/// Rebuild the database file, so the ciphertexts of deleted rows do not survive in free pages.
+/// It cannot run inside a transaction.
+pub fn vacuum(connection: &Connection) -> Result<(), DataStorageError> {
+    connection.execute_batch("VACUUM")?;
+    Ok(())
+}
+
+/// Retrieve all logins with their fields decrypted. Passwords stay encrypted.
+/// Fails if any field does not belong to its login.
+pub fn retrieve_all(
+    connection: &Connection,
+    cipher: &Cipher,
+) -> Result<Vec<LoginData>, DataStorageError> {
+    let decrypt = |login: &LoginData, field: &str, value: &str| {
+        cipher
+            .decrypt(value, &login.associated_data(field))
+            .ok()
+            .and_then(|decrypted| std::str::from_utf8(&decrypted).map(str::to_owned).ok())
+            .ok_or(DataStorageError::DecryptionFailed { uuid: login.uuid })
+    };
+    retrieve_rows(connection)?
+        .into_iter()
+        .map(|login| {
+            Ok(LoginData {
+                name: decrypt(&login, "name", &login.name)?,
+                username: decrypt(&login, "username", &login.username)?,
+                date_modified: decrypt(&login, "date_modified", &login.date_modified)?,
+                date_deleted: match &login.date_deleted {
+                    Some(date) => Some(decrypt(&login, "date_deleted", date)?),
+                    None => None,
+                },
+                ..login
+            })
+        })
+        .collect()
+}
+
+/// Retrieve the logins that are not in the trash, decrypted as by `retrieve_all`.
+pub fn retrieve_active(
+    connection: &Connection,
+    cipher: &Cipher,
+) -> Result<Vec<LoginData>, DataStorageError> {
+    let mut logins = retrieve_all(connection, cipher)?;
+    logins.retain(|login| !login.is_trashed());
+    Ok(logins)
+}
+
+/// Retrieve the logins in the trash, decrypted as by `retrieve_all`.
+pub fn retrieve_trash(
+    connection: &Connection,
+    cipher: &Cipher,
+) -> Result<Vec<LoginData>, DataStorageError> {
+    let mut logins = retrieve_all(connection, cipher)?;
+    logins.retain(LoginData::is_trashed);
+    Ok(logins)
+}
+
+/// Retrieve all rows as they are stored, in insertion order.
+pub fn retrieve_rows(connection: &Connection) -> Result<Vec<LoginData>, DataStorageError> {
+    let mut sql = connection
+        .prepare(
+            "SELECT uuid, name, username, date_modified, password, date_deleted FROM Accounts ORDER BY id",
+        )
+        .unwrap();
     let logins_iter = sql
         .query_map([], |row| {
             Ok(LoginData {
-                id: row.get(0)?,
+                uuid: row.get::<_, String>(0)?.parse().map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))
+                })?,
                 name: row.get(1)?,
                 username: row.get(2)?,
                 date_modified: row.get(3)?,
                 password: row.get(4)?,
+                date_deleted: row.get(5)?,
             })
         })
         .unwrap();
@@ -65,3 +437,305 @@ pub fn retrieve_all(connection: &Connection) -> Result<Vec<LoginData>, DataStora
 
     Ok(logins)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::cryptography::encryption::{Algorithm, KeyScheme};
+
+    #[test]
+    fn fields_are_encrypted() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        let login = LoginData::new(
+            "github".into(),
+            "me@example.com".into(),
+            b"hunter2",
+            &cipher,
+        );
+        insert_login(&conn, &cipher, login);
+
+        let stored = &retrieve_rows(&conn).unwrap()[0];
+        assert_ne!(stored.name, "github");
+        assert_ne!(stored.username, "me@example.com");
+
+        let login = &retrieve_all(&conn, &cipher).unwrap()[0];
+        assert_eq!(login.name, "github");
+        assert_eq!(login.username, "me@example.com");
+        assert_eq!(*login.decrypt_password(&cipher).unwrap(), b"hunter2");
+        assert!(retrieve_all(&conn, &Cipher::new(&[2u8; 32])).is_err());
+    }
+
+    #[test]
+    fn swapped_fields_are_rejected() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        for name in ["bank", "github"] {
+            let login = LoginData::new(name.into(), "me".into(), name.as_bytes(), &cipher);
+            insert_login(&conn, &cipher, login);
+        }
+
+        // Copy the github password into the bank entry
+        conn.execute(
+            "UPDATE Accounts SET password = (SELECT password FROM Accounts WHERE id = 2) WHERE id = 1",
+            (),
+        )
+        .unwrap();
+        let logins = retrieve_all(&conn, &cipher).unwrap();
+        assert!(logins[0].decrypt_password(&cipher).is_err());
+        assert_eq!(*logins[1].decrypt_password(&cipher).unwrap(), b"github");
+
+        // Swap the names
+        conn.execute("UPDATE Accounts SET name = username WHERE id = 2", ())
+            .unwrap();
+        assert!(retrieve_all(&conn, &cipher).is_err());
+    }
+
+    #[test]
+    fn update_in_place() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        for name in ["bank", "github"] {
+            let login = LoginData::new(name.into(), "me".into(), name.as_bytes(), &cipher);
+            insert_login(&conn, &cipher, login);
+        }
+
+        let mut login = retrieve_all(&conn, &cipher).unwrap().remove(0);
+        login.username = "other".into();
+        login.set_password(&cipher, b"hunter2");
+        update_login(&conn, &cipher, &login).unwrap();
+
+        let logins = retrieve_all(&conn, &cipher).unwrap();
+        assert_eq!(logins.len(), 2);
+        assert_eq!(logins[0].uuid, login.uuid);
+        assert_eq!(logins[0].username, "other");
+        assert_eq!(*logins[0].decrypt_password(&cipher).unwrap(), b"hunter2");
+        assert_eq!(*logins[1].decrypt_password(&cipher).unwrap(), b"github");
+    }
+
+    #[test]
+    fn trash_is_purged_and_emptied() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        for name in ["bank", "github", "mail"] {
+            let login = LoginData::new(name.into(), "me".into(), name.as_bytes(), &cipher);
+            insert_login(&conn, &cipher, login);
+        }
+        let logins = retrieve_all(&conn, &cipher).unwrap();
+        let (mut old, mut recent) = (logins[0].clone(), logins[1].clone());
+        old.date_deleted = Some("Mon, 1 Jan 2024 00:00:00 +0000".into());
+        recent.move_to_trash();
+        update_login(&conn, &cipher, &old).unwrap();
+        update_login(&conn, &cipher, &recent).unwrap();
+
+        assert_ne!(
+            retrieve_rows(&conn).unwrap()[0].date_deleted,
+            old.date_deleted
+        );
+        assert_eq!(retrieve_active(&conn, &cipher).unwrap().len(), 1);
+        let trash = retrieve_trash(&conn, &cipher).unwrap();
+        assert_eq!(trash[0].date_deleted, old.date_deleted);
+
+        assert_eq!(purge_trash(&conn, &cipher, 0).unwrap(), 0);
+        assert_eq!(purge_trash(&conn, &cipher, 30).unwrap(), 1);
+        assert_eq!(retrieve_trash(&conn, &cipher).unwrap()[0].uuid, recent.uuid);
+
+        recent.restore();
+        update_login(&conn, &cipher, &recent).unwrap();
+        assert_eq!(retrieve_active(&conn, &cipher).unwrap().len(), 2);
+
+        let mut login = retrieve_all(&conn, &cipher).unwrap().remove(0);
+        login.move_to_trash();
+        update_login(&conn, &cipher, &login).unwrap();
+        assert_eq!(empty_trash(&conn, &cipher).unwrap(), 1);
+        vacuum(&conn).unwrap();
+        let remaining = retrieve_all(&conn, &cipher).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].name, "mail");
+    }
+
+    #[test]
+    fn delete_keeps_other_identities() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        for name in ["bank", "github", "mail"] {
+            let login = LoginData::new(name.into(), "me".into(), name.as_bytes(), &cipher);
+            insert_login(&conn, &cipher, login);
+        }
+        let stored_ids = |conn: &Connection| -> Vec<(usize, String)> {
+            conn.prepare("SELECT id, uuid FROM Accounts ORDER BY id")
+                .unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let before = retrieve_all(&conn, &cipher).unwrap();
+        let ids = stored_ids(&conn);
+
+        delete_login(&conn, &before[0].uuid).unwrap();
+        let after = retrieve_all(&conn, &cipher).unwrap();
+        assert_eq!(after.len(), 2);
+        for (kept, login) in before[1..].iter().zip(&after) {
+            assert_eq!(kept.uuid, login.uuid);
+            assert_eq!(
+                *login.decrypt_password(&cipher).unwrap(),
+                kept.name.as_bytes()
+            );
+        }
+        // Rows are not renumbered either
+        assert_eq!(stored_ids(&conn), ids[1..]);
+    }
+
+    #[test]
+    fn legacy_rows_are_migrated() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE Accounts (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            username TEXT NOT NULL,
+            date_modified TEXT NOT NULL,
+            password TEXT NOT NULL
+        )",
+            (),
+        )
+        .unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        let password = cipher.encrypt_with(Algorithm::Fernet, b"hunter2", b"");
+        conn.execute(
+            "INSERT INTO Accounts (name, username, date_modified, password) VALUES ('github', 'me', 'today', ?1)",
+            [&password],
+        )
+        .unwrap();
+
+        migrate(&conn, &cipher).unwrap();
+        let login = &retrieve_all(&conn, &cipher).unwrap()[0];
+        assert_eq!(login.name, "github");
+        assert_eq!(login.date_modified, "today");
+        assert_eq!(*login.decrypt_password(&cipher).unwrap(), b"hunter2");
+        assert_ne!(retrieve_rows(&conn).unwrap()[0].name, "github");
+        migrate(&conn, &cipher).unwrap();
+    }
+
+    #[test]
+    fn migrations_end_at_data_version() {
+        let versions: Vec<i32> = MIGRATIONS.iter().map(|(version, _)| *version).collect();
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(versions.last(), Some(&DATA_VERSION));
+    }
+
+    #[test]
+    fn migration_is_preceded_by_a_backup() {
+        let path = std::env::temp_dir().join(format!(
+            "password-guardian-{}-legacy.db",
+            std::process::id()
+        ));
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(
+            "CREATE TABLE Accounts (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            username TEXT NOT NULL,
+            date_modified TEXT NOT NULL,
+            password TEXT NOT NULL
+        )",
+            (),
+        )
+        .unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        drop(conn);
+        let original = std::fs::read(&path).unwrap();
+
+        let conn = database_connection(&path, None).unwrap();
+        assert_eq!(std::fs::read(backup_path(&path, 1)).unwrap(), original);
+        // Data migrations wait for the cipher
+        assert_eq!(version(&conn).unwrap(), 1);
+        let transaction = conn.unchecked_transaction().unwrap();
+        migrate(&transaction, &Cipher::new(&[1u8; 32])).unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(version(&conn).unwrap(), DATA_VERSION);
+        assert_eq!(remove_backups(&path).unwrap(), 1);
+        assert!(!backup_path(&path, 1).exists());
+        assert_eq!(remove_backups(&path).unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn newer_data_version_is_refused() {
+        let path =
+            std::env::temp_dir().join(format!("password-guardian-{}-newer.db", std::process::id()));
+        let conn = Connection::open(&path).unwrap();
+        create_table(&conn).unwrap();
+        conn.pragma_update(None, "user_version", DATA_VERSION + 1)
+            .unwrap();
+        drop(conn);
+        assert!(matches!(
+            database_connection(&path, None),
+            Err(DataStorageError::UnsupportedDataVersion { .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reencrypt_under_new_key_scheme() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        let legacy = Cipher::with_scheme(&[1u8; 32], KeyScheme::Legacy);
+        let login = LoginData::new("github".into(), "me".into(), b"hunter2", &legacy);
+        insert_login(&conn, &legacy, login);
+
+        let cipher = legacy.rederive(KeyScheme::CURRENT);
+        reencrypt(&conn, &legacy, &cipher).unwrap();
+        assert!(retrieve_all(&conn, &legacy).is_err());
+        let login = &retrieve_all(&conn, &cipher).unwrap()[0];
+        assert_eq!(login.name, "github");
+        assert_eq!(*login.decrypt_password(&cipher).unwrap(), b"hunter2");
+    }
+
+    #[test]
+    #[cfg(feature = "sqlcipher")]
+    fn database_file_is_encrypted() {
+        let path = std::env::temp_dir().join(format!(
+            "password-guardian-{}-encrypted.db",
+            std::process::id()
+        ));
+        std::fs::File::create(&path).unwrap();
+        let conn = database_connection(&path, Some(&[3u8; 32])).unwrap();
+        create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        let login = LoginData::new("github".into(), "me".into(), b"hunter2", &cipher);
+        insert_login(&conn, &cipher, login);
+        drop(conn);
+
+        assert!(!std::fs::read(&path)
+            .unwrap()
+            .starts_with(b"SQLite format 3"));
+        // Without the key not even the data format can be read
+        assert!(database_connection(&path, None).is_err());
+        assert!(database_connection(&path, Some(&[4u8; 32])).is_err());
+        let conn = database_connection(&path, Some(&[3u8; 32])).unwrap();
+        assert_eq!(retrieve_all(&conn, &cipher).unwrap()[0].name, "github");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "sqlcipher"))]
+    fn database_file_encryption_needs_sqlcipher() {
+        let path = std::env::temp_dir().join(format!(
+            "password-guardian-{}-unsupported.db",
+            std::process::id()
+        ));
+        std::fs::File::create(&path).unwrap();
+        assert!(matches!(
+            database_connection(&path, Some(&[3u8; 32])),
+            Err(DataStorageError::PageEncryptionUnsupported)
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
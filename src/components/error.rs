@@ -1,10 +1,27 @@
 use std::{error::Error, fmt::Display, path::PathBuf};
 
+use uuid::Uuid;
+
 #[derive(Debug)]
 pub enum DataStorageError {
     FileNotFound { path: PathBuf },
     MetadataCorrupted { cause: String },
+    ContainerCorrupted { cause: String },
     DatabaseError { cause: rusqlite::Error },
+    IoError { cause: std::io::Error },
+    KdfError { cause: argon2::Error },
+    KdfDowngrade,
+    DecryptionFailed { uuid: Uuid },
+    IncorrectPassword,
+    PasswordUnchanged,
+    KeyfileRequired,
+    KeySlotNotFound,
+    LastKeySlot,
+    InvalidShares,
+    DuressPasswordInUse,
+    RotationInterrupted,
+    PageEncryptionUnsupported,
+    UnsupportedDataVersion { version: i32 },
 }
 
 impl From<rusqlite::Error> for DataStorageError {
@@ -13,6 +30,18 @@ impl From<rusqlite::Error> for DataStorageError {
     }
 }
 
+impl From<std::io::Error> for DataStorageError {
+    fn from(value: std::io::Error) -> Self {
+        DataStorageError::IoError { cause: value }
+    }
+}
+
+impl From<argon2::Error> for DataStorageError {
+    fn from(value: argon2::Error) -> Self {
+        DataStorageError::KdfError { cause: value }
+    }
+}
+
 impl Display for DataStorageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -26,6 +55,9 @@ impl Display for DataStorageError {
                     source
                 )
             }
+            DataStorageError::ContainerCorrupted { cause } => {
+                write!(f, "Unable to read the vault file: {}", cause)
+            }
             DataStorageError::DatabaseError { cause } => {
                 write!(
                     f,
@@ -33,6 +65,61 @@ impl Display for DataStorageError {
                     cause
                 )
             }
+            DataStorageError::IoError { cause } => {
+                write!(f, "Failed when accessing data files. Detail: {}", cause)
+            }
+            DataStorageError::KdfError { cause } => {
+                write!(
+                    f,
+                    "Failed when deriving key from password. Detail: {}",
+                    cause
+                )
+            }
+            DataStorageError::KdfDowngrade => {
+                write!(f, "The new key derivation parameters are weaker than the current ones.")
+            }
+            DataStorageError::DecryptionFailed { uuid } => {
+                write!(
+                    f,
+                    "Failed to decrypt item {}: it is corrupted or does not belong to this item",
+                    uuid
+                )
+            }
+            DataStorageError::IncorrectPassword => write!(f, "Incorrect master password."),
+            DataStorageError::PasswordUnchanged => {
+                write!(f, "New master password must differ from the current one.")
+            }
+            DataStorageError::KeyfileRequired => {
+                write!(f, "This vault can only be unlocked with its keyfile.")
+            }
+            DataStorageError::KeySlotNotFound => write!(f, "There is no such key slot."),
+            DataStorageError::LastKeySlot => {
+                write!(f, "The last key slot cannot be revoked.")
+            }
+            DataStorageError::PageEncryptionUnsupported => {
+                write!(f, "The database file is encrypted, which needs a build with the `sqlcipher` feature.")
+            }
+            DataStorageError::UnsupportedDataVersion { version } => {
+                write!(
+                    f,
+                    "The database uses data format {}, written by a newer version of this program. Please update it.",
+                    version
+                )
+            }
+            DataStorageError::DuressPasswordInUse => {
+                write!(
+                    f,
+                    "The duress password must differ from every password of the vault."
+                )
+            }
+            DataStorageError::RotationInterrupted => write!(
+                f,
+                "An encryption key rotation was interrupted. Unlock with a key slot it kept to finish it."
+            ),
+            DataStorageError::InvalidShares => write!(
+                f,
+                "Between 2 and 255 shares are required to unlock, and no more than are handed out."
+            ),
         }
     }
 }
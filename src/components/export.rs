@@ -12,9 +12,13 @@ where
     wtr.write_record(["name", "username", "password", "date"])?;
 
     for item in data {        
-        let password =
-            String::from_utf8(cipher.decrypt(&item.password)?)?;
-        wtr.write_record([&item.name, &item.username, &password, &item.date_modified])?;
+        let password = item.decrypt_password(cipher)?;
+        wtr.write_record([
+            item.name.as_bytes(),
+            item.username.as_bytes(),
+            &password,
+            item.date_modified.as_bytes(),
+        ])?;
     };
     
     wtr.flush()?;
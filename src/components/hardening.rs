@@ -0,0 +1,112 @@
+//! Process hardening against key material leaving memory.
+//!
+//! On Linux the process is made non-dumpable, which disables core dumps and ptrace attachment
+//! by other processes of the same user, and the pages holding keys, KEKs and decrypted
+//! passwords are locked into RAM so they are never written to swap. Locking only those pages
+//! fits in the default `RLIMIT_MEMLOCK`, unlike locking the whole process, Argon2 included.
+//! Other platforms are left as they are.
+
+use std::ops::{Deref, DerefMut};
+
+use zeroize::{Zeroize, Zeroizing};
+
+/// A secret on the heap, in pages locked into RAM, wiped from memory when dropped.
+/// It never moves, so it can be created empty and filled in place.
+pub struct Locked<T: Zeroize>(Box<Zeroizing<T>>);
+
+impl<T: Zeroize> Locked<T> {
+    pub fn new(value: T) -> Self {
+        let secret = Box::new(Zeroizing::new(value));
+        lock_memory(&**secret);
+        Self(secret)
+    }
+}
+
+impl<T: Zeroize> Deref for Locked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Locked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Harden the process before any secret is read.
+/// Returns a warning for every measure that could not be applied.
+#[cfg(target_os = "linux")]
+pub fn harden_process() -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    // SAFETY: `no_core` is a valid `rlimit` for the duration of the call.
+    unsafe {
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if libc::setrlimit(libc::RLIMIT_CORE, &no_core) != 0 {
+            warnings.push(format!(
+                "Cannot disable core dumps: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        if libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) != 0 {
+            warnings.push(format!(
+                "Cannot disable core dumps and debugger attachment: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    warnings
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn harden_process() -> Vec<String> {
+    Vec::new()
+}
+
+/// Lock the pages holding `value` into RAM.
+/// They stay locked for the rest of the run: other allocations may share them, and unlocking
+/// a page unlocks it for all of them. The first failure is reported, later ones are not.
+#[cfg(target_os = "linux")]
+pub fn lock_memory<T: ?Sized>(value: &T) {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+
+    let len = std::mem::size_of_val(value);
+    if len == 0 {
+        return;
+    }
+    // SAFETY: the range is the memory of `value`, which is valid for the duration of the call.
+    if unsafe { libc::mlock((value as *const T).cast(), len) } != 0 {
+        let error = std::io::Error::last_os_error();
+        WARNED.call_once(|| {
+            eprintln!("Warning: Cannot lock secrets into RAM ({error}), they may be written to swap.")
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lock_memory<T: ?Sized>(_: &T) {}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secrets_are_locked() {
+        let mut secret = Locked::new([0u8; 32]);
+        secret[0] = 7;
+        assert_eq!(secret[0], 7);
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let locked_kib: u64 = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmLck:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap();
+        assert!(locked_kib > 0);
+    }
+}
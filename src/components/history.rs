@@ -0,0 +1,224 @@
+//! Passwords replaced by an update, kept so they can be looked up or restored.
+//!
+//! Every entry belongs to a login by its UUID. Its fields are encrypted like the fields of the
+//! login, bound to the login UUID and a field name of their own, so a retired password cannot
+//! be passed off as a current one.
+
+use chrono::prelude::*;
+use rusqlite::types::Type;
+use rusqlite::Connection;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use super::cryptography::encryption::Cipher;
+use super::error::DataStorageError;
+use super::logindata::LoginData;
+
+#[derive(Debug, Clone)]
+pub struct PasswordHistory {
+    pub id: i64,
+    pub login: Uuid,
+    pub date_retired: String,
+    pub password: String,
+}
+
+impl PasswordHistory {
+    fn associated_data(login: &Uuid, field: &str) -> Vec<u8> {
+        format!("{}:history_{}", login, field).into_bytes()
+    }
+
+    fn decrypt_date(&self, cipher: &Cipher) -> Result<String, DataStorageError> {
+        cipher
+            .decrypt(
+                &self.date_retired,
+                &Self::associated_data(&self.login, "date_retired"),
+            )
+            .ok()
+            .and_then(|date| std::str::from_utf8(&date).map(str::to_owned).ok())
+            .ok_or(DataStorageError::DecryptionFailed { uuid: self.login })
+    }
+
+    pub fn decrypt_password(
+        &self,
+        cipher: &Cipher,
+    ) -> Result<Zeroizing<Vec<u8>>, DataStorageError> {
+        cipher
+            .decrypt(
+                &self.password,
+                &Self::associated_data(&self.login, "password"),
+            )
+            .map_err(|_| DataStorageError::DecryptionFailed { uuid: self.login })
+    }
+}
+
+pub fn create_table(connection: &Connection) -> Result<(), DataStorageError> {
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS PasswordHistory (
+            id INTEGER PRIMARY KEY,
+            login TEXT NOT NULL,
+            date_retired TEXT NOT NULL,
+            password TEXT NOT NULL
+        )",
+        (),
+    )?;
+    connection.execute(
+        "CREATE INDEX IF NOT EXISTS PasswordHistoryLogin ON PasswordHistory (login)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Keep the current password of `login` before it is replaced.
+pub fn retire_password(
+    connection: &Connection,
+    cipher: &Cipher,
+    login: &LoginData,
+) -> Result<(), DataStorageError> {
+    let password = login.decrypt_password(cipher)?;
+    let current_time: DateTime<Local> = DateTime::from(Utc::now());
+    let associated_data = |field| PasswordHistory::associated_data(&login.uuid, field);
+    connection.execute(
+        "INSERT INTO PasswordHistory (login, date_retired, password) VALUES (?1, ?2, ?3)",
+        [
+            login.uuid.to_string(),
+            cipher.encrypt(
+                current_time.to_rfc2822().as_bytes(),
+                &associated_data("date_retired"),
+            ),
+            cipher.encrypt(&password, &associated_data("password")),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Retired passwords of `login`, oldest first, with their dates decrypted.
+pub fn retrieve(
+    connection: &Connection,
+    cipher: &Cipher,
+    login: &Uuid,
+) -> Result<Vec<PasswordHistory>, DataStorageError> {
+    retrieve_rows(connection, Some(login))?
+        .into_iter()
+        .map(|entry| {
+            Ok(PasswordHistory {
+                date_retired: entry.decrypt_date(cipher)?,
+                ..entry
+            })
+        })
+        .collect()
+}
+
+/// Rows of `login`, or of every login, as they are stored.
+fn retrieve_rows(
+    connection: &Connection,
+    login: Option<&Uuid>,
+) -> Result<Vec<PasswordHistory>, DataStorageError> {
+    let mut sql = connection.prepare(
+        "SELECT id, login, date_retired, password FROM PasswordHistory
+        WHERE ?1 IS NULL OR login = ?1 ORDER BY id",
+    )?;
+    let rows = sql.query_map([login.map(Uuid::to_string)], |row| {
+        Ok(PasswordHistory {
+            id: row.get(0)?,
+            login: row.get::<_, String>(1)?.parse().map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(e))
+            })?,
+            date_retired: row.get(2)?,
+            password: row.get(3)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+pub fn delete(connection: &Connection, id: i64) -> Result<(), DataStorageError> {
+    connection.execute("DELETE FROM PasswordHistory WHERE id = ?1", [id])?;
+    Ok(())
+}
+
+/// Delete every retired password of `login`.
+pub fn delete_all(connection: &Connection, login: &Uuid) -> Result<(), DataStorageError> {
+    connection.execute(
+        "DELETE FROM PasswordHistory WHERE login = ?1",
+        [login.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Re-encrypt every retired password from `old_cipher` to `new_cipher`.
+/// Run it inside a transaction.
+pub fn reencrypt(
+    connection: &Connection,
+    old_cipher: &Cipher,
+    new_cipher: &Cipher,
+) -> Result<(), DataStorageError> {
+    for entry in retrieve_rows(connection, None)? {
+        let date_retired = entry.decrypt_date(old_cipher)?;
+        let password = entry.decrypt_password(old_cipher)?;
+        let associated_data = |field| PasswordHistory::associated_data(&entry.login, field);
+        connection.execute(
+            "UPDATE PasswordHistory SET date_retired = ?1, password = ?2 WHERE id = ?3",
+            (
+                new_cipher.encrypt(date_retired.as_bytes(), &associated_data("date_retired")),
+                new_cipher.encrypt(&password, &associated_data("password")),
+                entry.id,
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::database;
+
+    #[test]
+    fn retired_passwords_are_kept() {
+        let conn = Connection::open_in_memory().unwrap();
+        database::create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        let login = LoginData::new("github".into(), "me".into(), b"first", &cipher);
+        let other = LoginData::new("bank".into(), "me".into(), b"other", &cipher);
+        database::insert_login(&conn, &cipher, login.clone());
+        database::insert_login(&conn, &cipher, other.clone());
+
+        retire_password(&conn, &cipher, &login).unwrap();
+        let mut updated = login.clone();
+        updated.set_password(&cipher, b"second");
+        retire_password(&conn, &cipher, &updated).unwrap();
+        retire_password(&conn, &cipher, &other).unwrap();
+
+        let entries = retrieve(&conn, &cipher, &login.uuid).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(*entries[0].decrypt_password(&cipher).unwrap(), b"first");
+        assert_eq!(*entries[1].decrypt_password(&cipher).unwrap(), b"second");
+        assert!(!entries[0].date_retired.is_empty());
+
+        // A retired password is not a valid current password
+        let mut swapped = login.clone();
+        swapped.password = entries[0].password.clone();
+        assert!(swapped.decrypt_password(&cipher).is_err());
+
+        delete(&conn, entries[0].id).unwrap();
+        assert_eq!(retrieve(&conn, &cipher, &login.uuid).unwrap().len(), 1);
+        database::delete_login(&conn, &login.uuid).unwrap();
+        assert!(retrieve(&conn, &cipher, &login.uuid).unwrap().is_empty());
+        assert_eq!(retrieve(&conn, &cipher, &other.uuid).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn history_is_reencrypted() {
+        let conn = Connection::open_in_memory().unwrap();
+        database::create_table(&conn).unwrap();
+        let cipher = Cipher::new(&[1u8; 32]);
+        let login = LoginData::new("github".into(), "me".into(), b"old", &cipher);
+        database::insert_login(&conn, &cipher, login.clone());
+        retire_password(&conn, &cipher, &login).unwrap();
+
+        let new_cipher = Cipher::new(&[2u8; 32]);
+        database::reencrypt(&conn, &cipher, &new_cipher).unwrap();
+        assert!(retrieve(&conn, &cipher, &login.uuid).is_err());
+        let entries = retrieve(&conn, &new_cipher, &login.uuid).unwrap();
+        assert_eq!(*entries[0].decrypt_password(&new_cipher).unwrap(), b"old");
+    }
+}
@@ -0,0 +1,505 @@
+//! Tamper-evident integrity check of the `Accounts` and `PasswordHistory` tables.
+//!
+//! Every row is authenticated by an HMAC over its stored (encrypted) fields, and the list of
+//! login `(UUID, row MAC)` and retired password `((id, login UUID), row MAC)` pairs, the
+//! manifest, is authenticated as a whole. Both live in the `Integrity` table and are refreshed
+//! by `seal` after every legitimate write. Once a database has been sealed, the metadata
+//! records it, so dropping the table does not pass unnoticed.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+
+use hmac_sha256::HMAC;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::cryptography::encryption::{Cipher, Purpose};
+use super::database;
+use super::error::DataStorageError;
+
+/// Differences between the database and its last sealed state.
+#[derive(Debug, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// The manifest itself does not verify, so rows cannot be compared.
+    pub manifest_tampered: bool,
+    /// The database was sealed before, but the manifest is gone.
+    pub seal_removed: bool,
+    pub added: Vec<Item>,
+    pub removed: Vec<Item>,
+    pub modified: Vec<Item>,
+    /// `(item, previous item)` of content that was moved to another item.
+    pub reordered: Vec<(Item, Item)>,
+}
+
+/// An item named by an `IntegrityReport`.
+#[derive(Debug, PartialEq)]
+pub enum Item {
+    /// A login by UUID, with its name if it is stored and decrypts.
+    Login { uuid: String, name: Option<String> },
+    /// A retired password of a login, by the login UUID and name.
+    History { login: String, name: Option<String> },
+}
+
+impl IntegrityReport {
+    pub fn is_intact(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Display for IntegrityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Database integrity check failed:")?;
+        if self.manifest_tampered {
+            writeln!(f, "  - the integrity record itself has been modified")?;
+        }
+        if self.seal_removed {
+            writeln!(f, "  - the integrity record has been removed")?;
+        }
+        for item in &self.added {
+            writeln!(f, "  - {item} was added")?;
+        }
+        for item in &self.removed {
+            writeln!(f, "  - {item} was removed")?;
+        }
+        for item in &self.modified {
+            writeln!(f, "  - {item} was modified")?;
+        }
+        for (item, previous) in &self.reordered {
+            writeln!(
+                f,
+                "  - {item} holds the content previously stored in {previous}"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Item {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Item::Login {
+                uuid,
+                name: Some(name),
+            } => write!(f, "item \"{name}\" ({uuid})"),
+            Item::Login { uuid, name: None } => write!(f, "item {uuid}"),
+            Item::History {
+                login,
+                name: Some(name),
+            } => write!(f, "a previous password of item \"{name}\" ({login})"),
+            Item::History { login, name: None } => {
+                write!(f, "a previous password of item {login}")
+            }
+        }
+    }
+}
+
+/// The sealed state of the database.
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    /// `(UUID, MAC)` of every login, ordered by id.
+    logins: Vec<(String, String)>,
+    /// `((id, login UUID), MAC)` of every retired password, ordered by id.
+    history: Vec<(HistoryKey, String)>,
+}
+
+/// `(id, login UUID)` of a retired password.
+type HistoryKey = (i64, String);
+
+/// Record the current rows as the trusted state.
+pub fn seal(connection: &Connection, cipher: &Cipher) -> Result<(), DataStorageError> {
+    let key = cipher.subkey(Purpose::Integrity);
+    let manifest = Manifest {
+        logins: login_macs(connection, &key)?,
+        history: history_macs(connection, &key)?,
+    };
+    let manifest = serde_json::to_string(&manifest).unwrap();
+    let mac = base64_url::encode(&HMAC::mac(&manifest, key.as_ref()));
+
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Integrity (
+            manifest TEXT NOT NULL,
+            mac TEXT NOT NULL
+        )",
+        (),
+    )?;
+    connection.execute("DELETE FROM Integrity", ())?;
+    connection.execute(
+        "INSERT INTO Integrity (manifest, mac) VALUES (?1, ?2)",
+        [manifest, mac],
+    )?;
+    Ok(())
+}
+
+/// Compare the rows with the sealed state. Returns `None` if the database was never sealed,
+/// which is only accepted if it is not `expect_seal`ed.
+pub fn verify(
+    connection: &Connection,
+    cipher: &Cipher,
+    expect_seal: bool,
+) -> Result<Option<IntegrityReport>, DataStorageError> {
+    let has_table = connection
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'Integrity'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    let sealed: Option<(String, String)> = if has_table {
+        connection
+            .query_row("SELECT manifest, mac FROM Integrity", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?
+    } else {
+        None
+    };
+    let Some((sealed_manifest, sealed_mac)) = sealed else {
+        return Ok(expect_seal.then(|| IntegrityReport {
+            seal_removed: true,
+            ..Default::default()
+        }));
+    };
+
+    let key = cipher.subkey(Purpose::Integrity);
+    let mut report = IntegrityReport::default();
+    let sealed_mac: Option<[u8; 32]> = base64_url::decode(&sealed_mac)
+        .ok()
+        .and_then(|mac| mac.try_into().ok());
+    let expected: Manifest = match sealed_mac {
+        Some(mac) if HMAC::verify(&sealed_manifest, key.as_ref(), &mac) => {
+            serde_json::from_str(&sealed_manifest).unwrap_or_default()
+        }
+        _ => {
+            report.manifest_tampered = true;
+            return Ok(Some(report));
+        }
+    };
+
+    let names = login_names(connection, cipher);
+    let name = |uuid: &String| names.get(uuid).cloned().flatten();
+    let login = |uuid: &String| Item::Login {
+        uuid: uuid.clone(),
+        name: name(uuid),
+    };
+    let entry = |(_, uuid): &HistoryKey| Item::History {
+        login: uuid.clone(),
+        name: name(uuid),
+    };
+    let actual = login_macs(connection, &key)?;
+    compare(&expected.logins, &actual, &mut report, login);
+    let actual = history_macs(connection, &key)?;
+    compare(&expected.history, &actual, &mut report, entry);
+    Ok(Some(report))
+}
+
+/// Add the differences between the `expected` and `actual` `(key, MAC)` pairs to `report`,
+/// naming every key with `item`.
+fn compare<K: Eq + Hash>(
+    expected: &[(K, String)],
+    actual: &[(K, String)],
+    report: &mut IntegrityReport,
+    item: impl Fn(&K) -> Item,
+) {
+    let expected_keys: HashMap<&K, &String> = expected.iter().map(|(k, mac)| (k, mac)).collect();
+    let expected_macs: HashMap<&String, &K> = expected.iter().map(|(k, mac)| (mac, k)).collect();
+    let actual_macs: HashMap<&String, &K> = actual.iter().map(|(k, mac)| (mac, k)).collect();
+
+    let mut modified = Vec::new();
+    for (key, mac) in actual {
+        match expected_keys.get(key) {
+            Some(expected_mac) if *expected_mac == mac => {}
+            _ if let Some(previous) = expected_macs.get(mac) => {
+                report.reordered.push((item(key), item(previous)))
+            }
+            Some(_) => {
+                modified.push(key);
+                report.modified.push(item(key));
+            }
+            None => report.added.push(item(key)),
+        }
+    }
+    // Content that is nowhere to be found, unless it was reported as modified in place.
+    for (key, mac) in expected {
+        if !actual_macs.contains_key(mac) && !modified.contains(&key) {
+            report.removed.push(item(key));
+        }
+    }
+}
+
+/// `(UUID, MAC)` of every login, ordered by id.
+/// The MAC covers the stored fields but not the UUID, so moved content can be recognised.
+/// Only columns present in every data format since logins have a UUID are read, so older
+/// databases can be verified before they are migrated, plus the deletion date of rows in the
+/// trash. Rows outside the trash keep the MAC they had before the trash existed.
+fn login_macs(
+    connection: &Connection,
+    key: &[u8; 32],
+) -> Result<Vec<(String, String)>, DataStorageError> {
+    let has_trash = connection
+        .query_row(
+            "SELECT 1 FROM pragma_table_info('Accounts') WHERE name = 'date_deleted'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    let mut sql = connection.prepare(match has_trash {
+        true => "SELECT uuid, name, username, date_modified, password, date_deleted FROM Accounts ORDER BY id",
+        false => "SELECT uuid, name, username, date_modified, password, NULL FROM Accounts ORDER BY id",
+    })?;
+    let rows = sql.query_map([], |row| {
+        let mut fields: Vec<String> = vec![row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?];
+        fields.extend(row.get::<_, Option<String>>(5)?);
+        Ok((row.get(0)?, base64_url::encode(&row_mac(&fields, key))))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// `((id, login UUID), MAC)` of every retired password, ordered by id.
+/// The MAC covers the login UUID as well, so an entry cannot be moved to another login.
+fn history_macs(
+    connection: &Connection,
+    key: &[u8; 32],
+) -> Result<Vec<(HistoryKey, String)>, DataStorageError> {
+    let mut sql = connection
+        .prepare("SELECT id, login, date_retired, password FROM PasswordHistory ORDER BY id")?;
+    let rows = sql.query_map([], |row| {
+        let fields: Vec<String> = vec![row.get(1)?, row.get(2)?, row.get(3)?];
+        let mac = base64_url::encode(&row_mac(&fields, key));
+        Ok(((row.get(0)?, fields[0].clone()), mac))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn row_mac(fields: &[String], key: &[u8; 32]) -> [u8; 32] {
+    let mut mac = HMAC::new(key);
+    for field in fields {
+        mac.update((field.len() as u64).to_be_bytes());
+        mac.update(field);
+    }
+    mac.finalize()
+}
+
+/// Name of every login by UUID, `None` if it does not decrypt.
+/// Logins are left unnamed if any row does not hold a valid UUID.
+fn login_names(connection: &Connection, cipher: &Cipher) -> HashMap<String, Option<String>> {
+    database::retrieve_rows(connection)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|login| {
+            let name = cipher
+                .decrypt(&login.name, &login.associated_data("name"))
+                .ok()
+                .and_then(|name| std::str::from_utf8(&name).map(str::to_owned).ok());
+            (login.uuid.to_string(), name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::history;
+    use crate::components::logindata::LoginData;
+
+    fn sealed_database(cipher: &Cipher) -> (Connection, Vec<LoginData>) {
+        let conn = Connection::open_in_memory().unwrap();
+        database::create_table(&conn).unwrap();
+        for name in ["bank", "github", "mail"] {
+            let login = LoginData::new(name.into(), "me".into(), name.as_bytes(), cipher);
+            database::insert_login(&conn, cipher, login);
+        }
+        seal(&conn, cipher).unwrap();
+        let logins = database::retrieve_all(&conn, cipher).unwrap();
+        (conn, logins)
+    }
+
+    fn item(login: &LoginData, named: bool) -> Item {
+        Item::Login {
+            uuid: login.uuid.to_string(),
+            name: named.then(|| login.name.clone()),
+        }
+    }
+
+    #[test]
+    fn intact() {
+        let cipher = Cipher::new(&[1u8; 32]);
+        let (conn, _) = sealed_database(&cipher);
+        assert!(verify(&conn, &cipher, true).unwrap().unwrap().is_intact());
+    }
+
+    #[test]
+    fn unsealed() {
+        let conn = Connection::open_in_memory().unwrap();
+        database::create_table(&conn).unwrap();
+        assert!(verify(&conn, &Cipher::new(&[1u8; 32]), false)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn detects_changes() {
+        let cipher = Cipher::new(&[1u8; 32]);
+        let (conn, logins) = sealed_database(&cipher);
+        let [bank, github, mail] = &logins[..] else {
+            unreachable!()
+        };
+        conn.execute("DELETE FROM Accounts WHERE id = 1", ())
+            .unwrap();
+        conn.execute(
+            "UPDATE Accounts SET uuid = ?1 WHERE id = 2",
+            [bank.uuid.to_string()],
+        )
+        .unwrap();
+        conn.execute("UPDATE Accounts SET username = 'x' WHERE id = 3", ())
+            .unwrap();
+        let added = uuid::Uuid::new_v4().to_string();
+        conn.execute(
+            "INSERT INTO Accounts (id, name, username, date_modified, password, uuid) VALUES (4, 'a', 'b', 'c', 'd', ?1)",
+            [&added],
+        )
+        .unwrap();
+
+        let report = verify(&conn, &cipher, true).unwrap().unwrap();
+        assert_eq!(
+            report.reordered,
+            vec![(item(bank, false), item(github, false))]
+        );
+        assert_eq!(report.modified, vec![item(mail, true)]);
+        assert_eq!(
+            report.added,
+            vec![Item::Login {
+                uuid: added,
+                name: None
+            }]
+        );
+        assert_eq!(report.removed, vec![item(bank, false)]);
+        assert!(report
+            .to_string()
+            .contains(&format!("item \"mail\" ({}) was modified", mail.uuid)));
+    }
+
+    #[test]
+    fn detects_restore_from_trash() {
+        let cipher = Cipher::new(&[1u8; 32]);
+        let (conn, mut logins) = sealed_database(&cipher);
+        let mut login = logins.remove(1);
+        login.move_to_trash();
+        database::update_login(&conn, &cipher, &login).unwrap();
+        seal(&conn, &cipher).unwrap();
+
+        conn.execute("UPDATE Accounts SET date_deleted = NULL WHERE id = 2", ())
+            .unwrap();
+        assert_eq!(
+            verify(&conn, &cipher, true).unwrap().unwrap().modified,
+            vec![item(&login, true)]
+        );
+    }
+
+    #[test]
+    fn detects_history_changes() {
+        let cipher = Cipher::new(&[1u8; 32]);
+        let (conn, logins) = sealed_database(&cipher);
+        let [bank, github, mail] = &logins[..] else {
+            unreachable!()
+        };
+        for login in [bank, github, mail] {
+            history::retire_password(&conn, &cipher, login).unwrap();
+        }
+        seal(&conn, &cipher).unwrap();
+        assert!(verify(&conn, &cipher, true).unwrap().unwrap().is_intact());
+        let entry = |login: &LoginData| Item::History {
+            login: login.uuid.to_string(),
+            name: Some(login.name.clone()),
+        };
+
+        let mail_password: String = conn
+            .query_row(
+                "SELECT password FROM PasswordHistory WHERE id = 3",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        conn.execute(
+            "UPDATE PasswordHistory SET password = ?1 WHERE id = 1",
+            [&mail_password],
+        )
+        .unwrap();
+        conn.execute("DELETE FROM PasswordHistory WHERE id = 2", ())
+            .unwrap();
+        conn.execute(
+            "UPDATE PasswordHistory SET login = ?1 WHERE id = 3",
+            [bank.uuid.to_string()],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO PasswordHistory (id, login, date_retired, password) VALUES (4, ?1, 'a', ?2)",
+            [mail.uuid.to_string(), mail_password],
+        )
+        .unwrap();
+
+        let report = verify(&conn, &cipher, true).unwrap().unwrap();
+        assert_eq!(report.modified, vec![entry(bank)]);
+        assert_eq!(report.added, vec![entry(bank), entry(mail)]);
+        assert_eq!(report.removed, vec![entry(github), entry(mail)]);
+        assert!(report.reordered.is_empty());
+        assert!(report.to_string().contains(&format!(
+            "a previous password of item \"github\" ({}) was removed",
+            github.uuid
+        )));
+    }
+
+    #[test]
+    fn detects_restored_history() {
+        let cipher = Cipher::new(&[1u8; 32]);
+        let (conn, logins) = sealed_database(&cipher);
+        history::retire_password(&conn, &cipher, &logins[0]).unwrap();
+        let stored: (i64, String, String, String) = conn
+            .query_row("SELECT * FROM PasswordHistory", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap();
+        history::delete_all(&conn, &logins[0].uuid).unwrap();
+        seal(&conn, &cipher).unwrap();
+
+        conn.execute(
+            "INSERT INTO PasswordHistory VALUES (?1, ?2, ?3, ?4)",
+            stored,
+        )
+        .unwrap();
+        let report = verify(&conn, &cipher, true).unwrap().unwrap();
+        assert_eq!(
+            report.added,
+            vec![Item::History {
+                login: logins[0].uuid.to_string(),
+                name: Some("bank".into())
+            }]
+        );
+    }
+
+    #[test]
+    fn detects_tampered_manifest() {
+        let cipher = Cipher::new(&[1u8; 32]);
+        let (conn, _) = sealed_database(&cipher);
+        conn.execute("DELETE FROM Accounts WHERE id = 1", ())
+            .unwrap();
+        seal(&conn, &Cipher::new(&[2u8; 32])).unwrap();
+
+        let report = verify(&conn, &cipher, true).unwrap().unwrap();
+        assert!(report.manifest_tampered);
+    }
+
+    #[test]
+    fn detects_removed_seal() {
+        let cipher = Cipher::new(&[1u8; 32]);
+        for stripping in ["DELETE FROM Integrity", "DROP TABLE Integrity"] {
+            let (conn, _) = sealed_database(&cipher);
+            conn.execute(stripping, ()).unwrap();
+            let report = verify(&conn, &cipher, true).unwrap().unwrap();
+            assert!(report.seal_removed);
+            assert!(!report.is_intact());
+        }
+    }
+}
@@ -3,26 +3,83 @@
 //! `LoginData` is a set of data with the encrypted password and its relevant data
 
 use chrono::prelude::*;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use super::cryptography::encryption::Cipher;
+use super::error::DataStorageError;
 
 #[derive(Debug, Clone)]
 pub struct LoginData {
-    pub id: usize,
+    /// Permanent identity of the login, it never changes once created.
+    pub uuid: Uuid,
     pub name: String,
     pub username: String,
     pub date_modified: String,
     pub password: String,
+    /// When the login was moved to the trash, if it is there.
+    pub date_deleted: Option<String>,
+}
+
+fn now() -> String {
+    let current_time: DateTime<Local> = DateTime::from(Utc::now());
+    current_time.to_rfc2822()
 }
 
 impl LoginData {
-    pub fn new(name: String, username: String, password: String) -> Self {
-        let current_time: DateTime<Local> = DateTime::from(Utc::now());
-        let iso8601 = current_time.to_rfc2822();
-        Self {
-            id: 0,
+    /// Create a login with a new identity and encrypt `password` bound to it.
+    pub fn new(name: String, username: String, password: &[u8], cipher: &Cipher) -> Self {
+        let mut login = Self {
+            uuid: Uuid::new_v4(),
             name,
             username,
-            date_modified: iso8601,
-            password,
-        }
+            date_modified: String::new(),
+            password: String::new(),
+            date_deleted: None,
+        };
+        login.touch();
+        login.set_password(cipher, password);
+        login
+    }
+
+    /// Record the current time as the modification date.
+    pub fn touch(&mut self) {
+        self.date_modified = now();
+    }
+
+    /// Move the login to the trash. It is only deleted when the trash is purged or emptied.
+    pub fn move_to_trash(&mut self) {
+        self.date_deleted = Some(now());
+    }
+
+    pub fn restore(&mut self) {
+        self.date_deleted = None;
+    }
+
+    pub fn is_trashed(&self) -> bool {
+        self.date_deleted.is_some()
+    }
+
+    /// Associated data binding an encrypted field to this login: `UUID:FIELD`.
+    pub fn associated_data(&self, field: &str) -> Vec<u8> {
+        format!("{}:{}", self.uuid, field).into_bytes()
+    }
+
+    pub fn set_password(&mut self, cipher: &Cipher, password: &[u8]) {
+        self.password = cipher.encrypt(password, &self.associated_data("password"));
+    }
+
+    pub fn decrypt_password(
+        &self,
+        cipher: &Cipher,
+    ) -> Result<Zeroizing<Vec<u8>>, DataStorageError> {
+        cipher
+            .decrypt(&self.password, &self.associated_data("password"))
+            .map_err(|_| DataStorageError::DecryptionFailed { uuid: self.uuid })
+    }
+
+    /// Whether name or username contains the lowercase `query`.
+    pub fn matches(&self, query: &str) -> bool {
+        self.name.to_lowercase().contains(query) || self.username.to_lowercase().contains(query)
     }
 }
@@ -1,15 +1,156 @@
+use super::cryptography::encryption::{Cipher, KeyScheme, Purpose};
+use super::cryptography::generate_salt;
+use super::cryptography::kdf::{self, KdfParams};
+use super::cryptography::recovery::RecoveryKey;
+use super::hardening::Locked;
 use crate::error::DataStorageError;
+use hmac_sha256::HMAC;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::Path;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
 
 /// metadata file structure:
-/// 1. salts: salt for deriving kek.
-/// 2. wrap: `AES-KW(enc_key, kek)`
+/// 1. slots: key slots, each opening the same vault key.
+/// 2. lockout: number of consecutive failed unlocks after which only slots requiring a keyfile
+///    and the recovery key are accepted.
+/// 3. scheme: version of the `KeyScheme` deriving subkeys from the vault key.
+/// 4. database_key: if the whole database file is encrypted, its random page key wrapped under
+///    the database subkey, `AES-KW(page_key, DBKEY)`. Rotating the vault key only re-wraps it.
+/// 5. trash_retention: days a deleted item stays in the trash before it is purged, 0 keeps it
+///    until the trash is emptied. Absent means `DEFAULT_TRASH_RETENTION`.
+/// 6. database_sealed: whether the database carries an integrity seal, after which a missing
+///    seal means it was stripped. Absent in vaults whose database was never sealed.
+/// 7. mac: `HMAC-SHA256(MKEY, content)` of the other fields, `MKEY` is the metadata subkey.
+///
+/// Files written before key slots existed hold the fields of a single slot at the top level,
+/// with `salts: [salt]` instead of `salt`. Files written before the MAC existed have none.
+/// Files written before the key hierarchy have no scheme and use `KeyScheme::Legacy`.
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "MetadataFormat")]
 pub struct Metadata {
-    salts: [String; 1],
+    slots: Vec<KeySlot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lockout: Option<u32>,
+    #[serde(skip_serializing_if = "KeyScheme::is_legacy")]
+    scheme: KeyScheme,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trash_retention: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    database_sealed: bool,
+    #[serde(skip_serializing)]
+    mac: Option<String>,
+}
+
+/// Days a deleted item stays in the trash unless configured otherwise.
+pub const DEFAULT_TRASH_RETENTION: u32 = 30;
+
+/// Metadata as written to the file, with its MAC.
+#[derive(Serialize)]
+struct SealedMetadata<'a> {
+    #[serde(flatten)]
+    metadata: &'a Metadata,
+    mac: String,
+}
+
+/// A key slot wraps the vault key under its own password (and keyfile):
+/// 1. label: name given by the user.
+/// 2. salt: salt for deriving kek.
+/// 3. kdf: Argon2 parameters for deriving kek. Legacy vaults without it use `KdfParams::legacy`.
+/// 4. wrap: `AES-KW(enc_key, kek)`
+/// 5. keyfile: whether a keyfile is mixed into the kek. The keyfile itself is never stored.
+/// 6. recovery: whether the password is a `RecoveryKey`, which is normalised before deriving kek.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub label: String,
+    salt: String,
+    kdf: KdfParams,
     wrap: String,
+    #[serde(default)]
+    keyfile: bool,
+    #[serde(default)]
+    recovery: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MetadataFormat {
+    Slots {
+        slots: Vec<KeySlot>,
+        #[serde(default)]
+        lockout: Option<u32>,
+        #[serde(default = "legacy_scheme")]
+        scheme: KeyScheme,
+        #[serde(default)]
+        database_key: Option<String>,
+        #[serde(default)]
+        trash_retention: Option<u32>,
+        #[serde(default)]
+        database_sealed: bool,
+        #[serde(default)]
+        mac: Option<String>,
+    },
+    Legacy {
+        salts: [String; 1],
+        #[serde(default = "KdfParams::legacy")]
+        kdf: KdfParams,
+        wrap: String,
+        #[serde(default)]
+        keyfile: bool,
+    },
+}
+
+impl From<MetadataFormat> for Metadata {
+    fn from(value: MetadataFormat) -> Self {
+        match value {
+            MetadataFormat::Slots {
+                slots,
+                lockout,
+                scheme,
+                database_key,
+                trash_retention,
+                database_sealed,
+                mac,
+            } => Self {
+                slots,
+                lockout,
+                scheme,
+                database_key,
+                trash_retention,
+                database_sealed,
+                mac,
+            },
+            MetadataFormat::Legacy {
+                salts: [salt],
+                kdf,
+                wrap,
+                keyfile,
+            } => Self {
+                slots: vec![KeySlot {
+                    label: "default".into(),
+                    salt,
+                    kdf,
+                    wrap,
+                    keyfile,
+                    recovery: false,
+                }],
+                lockout: None,
+                scheme: KeyScheme::Legacy,
+                database_key: None,
+                trash_retention: None,
+                database_sealed: false,
+                mac: None,
+            },
+        }
+    }
+}
+
+fn legacy_scheme() -> KeyScheme {
+    KeyScheme::Legacy
 }
 
 impl Metadata {
@@ -24,12 +165,318 @@ impl Metadata {
             serde_json::from_str(&data).map_err(|_| DataStorageError::MetadataCorrupted {
                 cause: "file is not a propper json object metadata.".into(),
             })?;
+        if x.slots.is_empty() {
+            return Err(DataStorageError::MetadataCorrupted {
+                cause: "no key slot left.".into(),
+            });
+        }
         Ok(x)
     }
 
-    pub fn new(wrap: String, salts: [String; 1]) -> Self {
-        Self { wrap, salts }
+    pub fn new(slots: Vec<KeySlot>) -> Self {
+        Self {
+            slots,
+            lockout: None,
+            scheme: KeyScheme::CURRENT,
+            database_key: None,
+            trash_retention: None,
+            database_sealed: false,
+            mac: None,
+        }
+    }
+    /// Check that the metadata was written by someone holding the encryption key of `cipher`.
+    /// Metadata written before it was authenticated passes, and is sealed when next written.
+    /// A missing MAC is only accepted in that shape, so stripping it does not turn the check off.
+    pub fn verify(&self, cipher: &Cipher) -> Result<(), DataStorageError> {
+        let Some(mac) = &self.mac else {
+            return match self.predates_mac() {
+                true => Ok(()),
+                false => Err(DataStorageError::MetadataCorrupted {
+                    cause: "its authentication code is missing, it has been tampered with.".into(),
+                }),
+            };
+        };
+        let mac: Option<[u8; 32]> = base64_url::decode(mac)
+            .ok()
+            .and_then(|mac| mac.try_into().ok());
+        match mac {
+            Some(mac)
+                if HMAC::verify(
+                    self.content(),
+                    cipher.subkey(Purpose::Metadata).as_ref(),
+                    &mac,
+                ) =>
+            {
+                Ok(())
+            }
+            _ => Err(DataStorageError::MetadataCorrupted {
+                cause: "its authentication code does not match, it has been tampered with.".into(),
+            }),
+        }
+    }
+    /// Whether the metadata carries a MAC.
+    pub fn is_sealed(&self) -> bool {
+        self.mac.is_some()
+    }
+    /// Whether the metadata could have been written before the MAC existed: no field added
+    /// since then is set. Legacy single slot files always are.
+    fn predates_mac(&self) -> bool {
+        self.scheme.is_legacy()
+            && self.lockout.is_none()
+            && self.database_key.is_none()
+            && self.trash_retention.is_none()
+            && !self.database_sealed
+    }
+    pub fn scheme(&self) -> KeyScheme {
+        self.scheme
+    }
+    /// Record the key scheme of the vault. The vault must be re-encrypted with it.
+    pub fn set_scheme(&mut self, scheme: KeyScheme) {
+        self.scheme = scheme;
+    }
+    /// Page key of the database file, if the whole file is encrypted.
+    pub fn database_key(
+        &self,
+        cipher: &Cipher,
+    ) -> Result<Option<Zeroizing<[u8; 32]>>, DataStorageError> {
+        let Some(wrap) = &self.database_key else {
+            return Ok(None);
+        };
+        let wrap: Option<[u8; 40]> = base64_url::decode(wrap)
+            .ok()
+            .and_then(|wrap| wrap.try_into().ok());
+        wrap.and_then(|wrap| cipher.unwrap_key(Purpose::Database, wrap).ok())
+            .map(Some)
+            .ok_or(DataStorageError::MetadataCorrupted {
+                cause: "the database key cannot be unwrapped.".into(),
+            })
+    }
+    /// Record the page key of the database file, wrapped under the database subkey of `cipher`.
+    pub fn set_database_key(&mut self, cipher: &Cipher, key: Option<&[u8; 32]>) {
+        self.database_key =
+            key.map(|key| base64_url::encode(&cipher.wrap_key(Purpose::Database, key)));
+    }
+    pub fn lockout(&self) -> Option<u32> {
+        self.lockout
+    }
+    pub fn set_lockout(&mut self, lockout: Option<u32>) {
+        self.lockout = lockout;
+    }
+    /// Days a deleted item stays in the trash, 0 if it stays until the trash is emptied.
+    pub fn trash_retention(&self) -> u32 {
+        self.trash_retention.unwrap_or(DEFAULT_TRASH_RETENTION)
+    }
+    pub fn set_trash_retention(&mut self, days: u32) {
+        self.trash_retention = Some(days).filter(|days| *days != DEFAULT_TRASH_RETENTION);
+    }
+    /// Whether the database has been sealed, so it must carry an integrity seal.
+    pub fn database_sealed(&self) -> bool {
+        self.database_sealed
+    }
+    pub fn set_database_sealed(&mut self) {
+        self.database_sealed = true;
+    }
+    pub fn slots(&self) -> &[KeySlot] {
+        &self.slots
+    }
+    pub fn slot_mut(&mut self, index: usize) -> Result<&mut KeySlot, DataStorageError> {
+        self.slots
+            .get_mut(index)
+            .ok_or(DataStorageError::KeySlotNotFound)
+    }
+    pub fn add_slot(&mut self, slot: KeySlot) {
+        self.slots.push(slot);
+    }
+    /// Remove a key slot. The last one cannot be removed.
+    pub fn remove_slot(&mut self, index: usize) -> Result<KeySlot, DataStorageError> {
+        if index >= self.slots.len() {
+            return Err(DataStorageError::KeySlotNotFound);
+        }
+        if self.slots.len() == 1 {
+            return Err(DataStorageError::LastKeySlot);
+        }
+        Ok(self.slots.remove(index))
+    }
+    /// Whether no master password slot can be opened without a keyfile.
+    pub fn requires_keyfile(&self) -> bool {
+        self.slots
+            .iter()
+            .filter(|slot| !slot.recovery)
+            .all(|slot| slot.keyfile)
+    }
+    /// Open the first key slot accepting the master password (and keyfile) or recovery key.
+    /// Returns the index of the slot with the unwrapped cipher.
+    pub fn unlock(
+        &self,
+        password: &str,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<(usize, Cipher), DataStorageError> {
+        self.unlock_slots(password, keyfile, false, false)
+    }
+    /// Like `unlock`, but once the vault is locked out password-only slots are skipped.
+    pub fn unlock_after(
+        &self,
+        failures: u32,
+        password: &str,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<(usize, Cipher), DataStorageError> {
+        let locked_out = self.lockout.is_some_and(|lockout| failures >= lockout);
+        self.unlock_slots(password, keyfile, locked_out, false)
+    }
+    /// Like `unlock_after`, but keeps deriving the KEK of every remaining slot after one opens,
+    /// so the time taken does not tell which slot, if any, accepted the password.
+    pub fn unlock_every_slot(
+        &self,
+        failures: u32,
+        password: &str,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<(usize, Cipher), DataStorageError> {
+        let locked_out = self.lockout.is_some_and(|lockout| failures >= lockout);
+        self.unlock_slots(password, keyfile, locked_out, true)
+    }
+    fn unlock_slots(
+        &self,
+        password: &str,
+        keyfile: Option<&[u8; 32]>,
+        locked_out: bool,
+        every_slot: bool,
+    ) -> Result<(usize, Cipher), DataStorageError> {
+        if keyfile.is_none() && self.slots.iter().all(|slot| slot.keyfile) {
+            return Err(DataStorageError::KeyfileRequired);
+        }
+        let mut unlocked = None;
+        for (index, slot) in self.slots.iter().enumerate() {
+            if slot.keyfile && keyfile.is_none() {
+                continue;
+            }
+            if locked_out && !slot.keyfile && !slot.recovery {
+                continue;
+            }
+            match slot.unlock(password, keyfile, self.scheme) {
+                Ok(cipher) if unlocked.is_none() => {
+                    unlocked = Some((index, cipher));
+                    if !every_slot {
+                        break;
+                    }
+                }
+                Ok(_) | Err(DataStorageError::IncorrectPassword) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        unlocked.ok_or(DataStorageError::IncorrectPassword)
+    }
+    /// Replace the metadata file atomically, authenticated with the key of `cipher`.
+    /// The new content is written next to `path` and renamed over it, so the old
+    /// metadata stays intact if anything fails half-way.
+    pub fn write_metadata(&self, path: &Path, cipher: &Cipher) -> Result<(), DataStorageError> {
+        if !path.exists() {
+            return Err(DataStorageError::FileNotFound {
+                path: path.to_path_buf(),
+            });
+        }
+        let temp_path = path.with_extension("json.tmp");
+        self.write_synced(&temp_path, cipher)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Location of metadata staged by `write_staged` for the metadata file at `path`.
+    pub fn staged_path(path: &Path) -> PathBuf {
+        path.with_extension("json.new")
+    }
+    /// Write the metadata next to `path` without replacing it.
+    /// Used when the database has to be committed before the metadata may change,
+    /// `commit_staged` then swaps it in.
+    pub fn write_staged(&self, path: &Path, cipher: &Cipher) -> Result<(), DataStorageError> {
+        self.write_synced(&Self::staged_path(path), cipher)
+    }
+    /// Replace the metadata file at `path` with the staged one.
+    pub fn commit_staged(path: &Path) -> Result<(), DataStorageError> {
+        fs::rename(Self::staged_path(path), path)?;
+        Ok(())
     }
+
+    /// Fields covered by the MAC, serialized.
+    fn content(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+
+    fn write_synced(&self, path: &Path, cipher: &Cipher) -> Result<(), DataStorageError> {
+        let mac = HMAC::mac(self.content(), cipher.subkey(Purpose::Metadata).as_ref());
+        let j = serde_json::to_string(&SealedMetadata {
+            metadata: self,
+            mac: base64_url::encode(&mac),
+        })
+        .unwrap();
+        let mut file = File::create(path)?;
+        file.write_all(j.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl KeySlot {
+    /// Wrap the encryption key of `cipher` under a KEK derived from `password` and `keyfile`
+    /// with `kdf` and a fresh salt.
+    pub fn new(
+        label: String,
+        cipher: &Cipher,
+        password: &str,
+        kdf: KdfParams,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<Self, DataStorageError> {
+        Self::create(label, false, cipher, password, kdf, keyfile)
+    }
+
+    /// Wrap the encryption key of `cipher` under a KEK derived from the recovery key.
+    /// Recovery slots never require a keyfile.
+    pub fn recovery(
+        label: String,
+        cipher: &Cipher,
+        key: &RecoveryKey,
+        kdf: KdfParams,
+    ) -> Result<Self, DataStorageError> {
+        Self::create(label, true, cipher, &key.canonical(), kdf, None)
+    }
+
+    /// A slot of the same kind and label, with a new password, parameters and salt.
+    pub fn renew(
+        &self,
+        cipher: &Cipher,
+        password: &str,
+        kdf: KdfParams,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<Self, DataStorageError> {
+        Self::create(
+            self.label.clone(),
+            self.recovery,
+            cipher,
+            password,
+            kdf,
+            keyfile,
+        )
+    }
+
+    fn create(
+        label: String,
+        recovery: bool,
+        cipher: &Cipher,
+        password: &str,
+        kdf: KdfParams,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<Self, DataStorageError> {
+        let mut slot = Self {
+            label,
+            salt: base64_url::encode(&generate_salt()),
+            kdf,
+            wrap: String::new(),
+            keyfile: keyfile.is_some(),
+            recovery,
+        };
+        slot.rewrap(cipher, &*slot.derive_kek(password, keyfile)?);
+        Ok(slot)
+    }
+
     pub fn wrap(&self) -> Result<[u8; 40], DataStorageError> {
         base64_url::decode(&self.wrap)
             .map_err(|_| DataStorageError::MetadataCorrupted {
@@ -41,18 +488,256 @@ impl Metadata {
             })
     }
     pub fn kek_salt(&self) -> Result<Vec<u8>, DataStorageError> {
-        base64_url::decode(&self.salts[0]).map_err(|_| DataStorageError::MetadataCorrupted {
+        base64_url::decode(&self.salt).map_err(|_| DataStorageError::MetadataCorrupted {
             cause: "data value is tampered.".into(),
         })
     }
-    pub fn write_metadata(&self, path: &Path) -> Result<(), DataStorageError> {
-        if !path.exists() {
-            return Err(DataStorageError::FileNotFound {
-                path: path.to_path_buf(),
-            });
+    pub fn kdf(&self) -> &KdfParams {
+        &self.kdf
+    }
+    pub fn requires_keyfile(&self) -> bool {
+        self.keyfile
+    }
+    pub fn is_recovery(&self) -> bool {
+        self.recovery
+    }
+    /// Derive kek from the master password with the recorded salt and parameters.
+    /// `keyfile` is the keyfile hash, only used if the slot requires one.
+    /// Recovery slots expect the recovery key as password, in any accepted spelling.
+    pub fn derive_kek(
+        &self,
+        password: &str,
+        keyfile: Option<&[u8; 32]>,
+    ) -> Result<Locked<[u8; 32]>, DataStorageError> {
+        let password = match self.recovery {
+            true => RecoveryKey::parse(password)
+                .ok_or(DataStorageError::IncorrectPassword)?
+                .canonical(),
+            false => Zeroizing::new(password.to_owned()),
+        };
+        let keyfile = match (self.keyfile, keyfile) {
+            (true, None) => return Err(DataStorageError::KeyfileRequired),
+            (true, keyfile) => keyfile,
+            (false, _) => None,
+        };
+        kdf::derive_kek(&password, &self.kek_salt()?, &self.kdf, keyfile).map_err(|e| {
+            DataStorageError::MetadataCorrupted {
+                cause: format!("invalid key derivation parameters ({e})."),
+            }
+        })
+    }
+    /// Unwrap the vault key with the master password, deriving its subkeys with `scheme`.
+    pub fn unlock(
+        &self,
+        password: &str,
+        keyfile: Option<&[u8; 32]>,
+        scheme: KeyScheme,
+    ) -> Result<Cipher, DataStorageError> {
+        let kek = self.derive_kek(password, keyfile)?;
+        Cipher::from_unwrap(&kek, self.wrap()?, scheme)
+            .map_err(|_| DataStorageError::IncorrectPassword)
+    }
+    /// Wrap the encryption key of `cipher` under `kek`, replacing the wrapped key.
+    pub fn rewrap(&mut self, cipher: &Cipher, kek: &[u8; 32]) {
+        self.wrap = base64_url::encode(&cipher.wrap(kek));
+    }
+}
+
+impl Display for KeySlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.label, self.kdf)?;
+        if self.keyfile {
+            write!(f, ", keyfile")?;
+        }
+        if self.recovery {
+            write!(f, ", recovery key")?;
         }
-        let j = serde_json::to_string(&self).unwrap();
-        fs::write(path, j).unwrap();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn any_slot_unlocks() {
+        let cipher = Cipher::generate();
+        let kdf = KdfParams::argon2id(64, 1);
+        let mut metadata = Metadata::new(vec![KeySlot::new(
+            "personal".into(),
+            &cipher,
+            "personal",
+            kdf.clone(),
+            None,
+        )
+        .unwrap()]);
+        metadata.add_slot(
+            KeySlot::new(
+                "recovery".into(),
+                &cipher,
+                "recovery",
+                kdf,
+                Some(&[1u8; 32]),
+            )
+            .unwrap(),
+        );
+
+        let encrypted = cipher.encrypt(b"secret", b"");
+        let (index, unlocked) = metadata.unlock("recovery", Some(&[1u8; 32])).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(*unlocked.decrypt(&encrypted, b"").unwrap(), b"secret");
+        assert_eq!(metadata.unlock("personal", None).unwrap().0, 0);
+        assert!(metadata.unlock("recovery", None).is_err());
+
+        metadata.remove_slot(0).unwrap();
+        assert!(metadata.unlock("personal", None).is_err());
+        assert!(metadata.remove_slot(0).is_err());
+    }
+
+    #[test]
+    fn recovery_key_unlocks() {
+        let cipher = Cipher::generate();
+        let kdf = KdfParams::argon2id(64, 1);
+        let key = RecoveryKey::generate();
+        let metadata = Metadata::new(vec![
+            KeySlot::new(
+                "personal".into(),
+                &cipher,
+                "pw",
+                kdf.clone(),
+                Some(&[1u8; 32]),
+            )
+            .unwrap(),
+            KeySlot::recovery("recovery key".into(), &cipher, &key, kdf).unwrap(),
+        ]);
+
+        assert!(metadata.requires_keyfile());
+        let typed = key.to_string().to_lowercase();
+        assert_eq!(metadata.unlock(&typed, None).unwrap().0, 1);
+        assert!(metadata.unlock("pw", None).is_err());
+    }
+
+    #[test]
+    fn lockout_skips_password_slots() {
+        let cipher = Cipher::generate();
+        let kdf = KdfParams::argon2id(64, 1);
+        let key = RecoveryKey::generate();
+        let mut metadata = Metadata::new(vec![
+            KeySlot::new("personal".into(), &cipher, "pw", kdf.clone(), None).unwrap(),
+            KeySlot::recovery("recovery key".into(), &cipher, &key, kdf).unwrap(),
+        ]);
+        metadata.set_lockout(Some(5));
+
+        assert!(metadata.unlock_after(4, "pw", None).is_ok());
+        assert!(metadata.unlock_after(5, "pw", None).is_err());
+        assert!(metadata.unlock_after(5, &key.to_string(), None).is_ok());
+    }
+
+    #[test]
+    fn every_slot_unlocks_like_the_first() {
+        let cipher = Cipher::generate();
+        let kdf = KdfParams::argon2id(64, 1);
+        let metadata = Metadata::new(vec![
+            KeySlot::new("personal".into(), &cipher, "pw", kdf.clone(), None).unwrap(),
+            KeySlot::new("shared".into(), &cipher, "pw", kdf.clone(), None).unwrap(),
+            KeySlot::new("work".into(), &cipher, "work", kdf, None).unwrap(),
+        ]);
+
+        assert_eq!(metadata.unlock_every_slot(0, "pw", None).unwrap().0, 0);
+        assert_eq!(metadata.unlock_every_slot(0, "work", None).unwrap().0, 2);
+        assert!(matches!(
+            metadata.unlock_every_slot(0, "duress", None),
+            Err(DataStorageError::IncorrectPassword)
+        ));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let cipher = Cipher::generate();
+        let kdf = KdfParams::argon2id(64, 1);
+        let path = std::env::temp_dir().join(format!(
+            "password-guardian-{}-metadata.json",
+            std::process::id()
+        ));
+        File::create(&path).unwrap();
+        let mut metadata = Metadata::new(vec![KeySlot::new(
+            "personal".into(),
+            &cipher,
+            "pw",
+            kdf.clone(),
+            None,
+        )
+        .unwrap()]);
+        metadata.write_metadata(&path, &cipher).unwrap();
+        let written = Metadata::from_file(&path).unwrap();
+        assert!(written.is_sealed());
+        written.verify(&cipher).unwrap();
+
+        // Replace the slot by one opening the attacker's key
+        let attacker = Cipher::generate();
+        metadata.slots[0] = KeySlot::new("personal".into(), &attacker, "pw", kdf, None).unwrap();
+        let mut content: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        content["slots"] = serde_json::to_value(&metadata.slots).unwrap();
+        fs::write(&path, content.to_string()).unwrap();
+
+        let tampered = Metadata::from_file(&path).unwrap();
+        let (_, unlocked) = tampered.unlock("pw", None).unwrap();
+        assert!(tampered.verify(&unlocked).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn legacy_format() {
+        let legacy = r#"{"salts":["AAAAAAAAAAAAAAAAAAAAAA"],"wrap":"AAAA"}"#;
+        let metadata: Metadata = serde_json::from_str(legacy).unwrap();
+        assert_eq!(metadata.slots().len(), 1);
+        assert_eq!(*metadata.slots()[0].kdf(), KdfParams::legacy());
+        assert!(!metadata.requires_keyfile());
+        assert_eq!(metadata.scheme(), KeyScheme::Legacy);
+        let written = serde_json::to_string(&metadata).unwrap();
+        assert!(written.starts_with(r#"{"slots":[{"label":"default","salt""#));
+        // Content authenticated before the scheme was recorded stays the same
+        assert!(!written.contains("scheme"));
+        let current = serde_json::to_string(&Metadata::new(metadata.slots.clone())).unwrap();
+        assert!(current.ends_with(r#""scheme":2}"#));
+        let reread: Metadata = serde_json::from_str(&current).unwrap();
+        assert_eq!(reread.scheme(), KeyScheme::CURRENT);
+    }
+
+    #[test]
+    fn missing_mac_is_detected() {
+        let cipher = Cipher::generate();
+        let kdf = KdfParams::argon2id(64, 1);
+        let slot = KeySlot::new("personal".into(), &cipher, "pw", kdf, None).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "password-guardian-{}-unsealed.json",
+            std::process::id()
+        ));
+        File::create(&path).unwrap();
+        Metadata::new(vec![slot.clone()])
+            .write_metadata(&path, &cipher)
+            .unwrap();
+
+        let mut content: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        content.as_object_mut().unwrap().remove("mac");
+        fs::write(&path, content.to_string()).unwrap();
+        let stripped = Metadata::from_file(&path).unwrap();
+        assert!(!stripped.is_sealed());
+        assert!(matches!(
+            stripped.verify(&cipher),
+            Err(DataStorageError::MetadataCorrupted { .. })
+        ));
+        fs::remove_file(path).unwrap();
+
+        // Files written before the MAC existed still pass
+        let legacy = Cipher::with_scheme(&[1u8; 32], KeyScheme::Legacy);
+        let mut unsealed = Metadata::new(vec![slot]);
+        unsealed.scheme = KeyScheme::Legacy;
+        unsealed.verify(&legacy).unwrap();
+        unsealed.set_lockout(Some(3));
+        assert!(unsealed.verify(&legacy).is_err());
+    }
+}
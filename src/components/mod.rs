@@ -1,7 +1,12 @@
 pub mod console;
+pub mod container;
 pub mod cryptography;
 pub mod database;
 pub mod error;
+pub mod hardening;
+pub mod history;
+pub mod integrity;
 pub mod metadata;
 pub mod export;
 pub mod logindata;
+pub mod throttle;
@@ -0,0 +1,159 @@
+//! Persistent record of failed unlock attempts.
+//!
+//! Failures are counted across runs in a file next to the metadata, so restarting the program
+//! does not reset the back-off between attempts.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::error::DataStorageError;
+
+/// Failures allowed before any back-off applies.
+const FREE_ATTEMPTS: u32 = 3;
+/// Longest wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Number of failure timestamps kept for the report.
+const KEPT_TIMESTAMPS: usize = 10;
+
+/// Failed unlock attempts since the last successful one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Attempts {
+    failures: u32,
+    /// Unix timestamps in milliseconds of the latest failures, oldest first.
+    timestamps: Vec<i64>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Attempts {
+    /// Load the record at `path`. A missing file means no failed attempts.
+    pub fn load(path: &Path) -> Result<Self, DataStorageError> {
+        let mut attempts: Attempts = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(path)?).map_err(|_| {
+                DataStorageError::MetadataCorrupted {
+                    cause: "record of failed attempts is not a propper json object.".into(),
+                }
+            })?
+        } else {
+            Attempts::default()
+        };
+        attempts.path = path.to_path_buf();
+        Ok(attempts)
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Times of the latest failures, oldest first.
+    pub fn timestamps(&self) -> impl Iterator<Item = DateTime<Local>> + '_ {
+        self.timestamps
+            .iter()
+            .filter_map(|timestamp| DateTime::from_timestamp_millis(*timestamp))
+            .map(|time| time.with_timezone(&Local))
+    }
+
+    /// Time left at `now` before the next attempt is allowed.
+    /// The wait doubles with every failure after the first `FREE_ATTEMPTS`.
+    pub fn delay(&self, now: DateTime<Utc>) -> Duration {
+        let Some(last) = self.timestamps.last() else {
+            return Duration::ZERO;
+        };
+        let backoff = match self.failures.checked_sub(FREE_ATTEMPTS) {
+            None => return Duration::ZERO,
+            Some(exponent) => 2u64
+                .checked_pow(exponent)
+                .map_or(MAX_BACKOFF, Duration::from_secs)
+                .min(MAX_BACKOFF),
+        };
+        // A clock set back in time does not shorten the wait.
+        let elapsed = (now.timestamp_millis() - last).max(0) as u64;
+        backoff.saturating_sub(Duration::from_millis(elapsed))
+    }
+
+    /// Record a failure at `now`.
+    pub fn record_failure(&mut self, now: DateTime<Utc>) -> Result<(), DataStorageError> {
+        self.failures = self.failures.saturating_add(1);
+        self.timestamps.push(now.timestamp_millis());
+        if self.timestamps.len() > KEPT_TIMESTAMPS {
+            self.timestamps.remove(0);
+        }
+        self.save()
+    }
+
+    /// Forget the failed attempts after a successful unlock.
+    pub fn record_success(&mut self) -> Result<(), DataStorageError> {
+        if self.failures == 0 {
+            return Ok(());
+        }
+        self.failures = 0;
+        self.timestamps.clear();
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), DataStorageError> {
+        let temp_path = self.path.with_extension("json.tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(serde_json::to_string(self).unwrap().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "password-guardian-{}-{name}.json",
+            std::process::id()
+        ));
+        _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn persisted_across_loads() {
+        let path = temp_path("persisted");
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let mut attempts = Attempts::load(&path).unwrap();
+        for _ in 1..FREE_ATTEMPTS {
+            attempts.record_failure(now).unwrap();
+        }
+        assert_eq!(attempts.delay(now), Duration::ZERO);
+        attempts.record_failure(now).unwrap();
+
+        let mut attempts = Attempts::load(&path).unwrap();
+        assert_eq!(attempts.failures(), FREE_ATTEMPTS);
+        assert_eq!(attempts.timestamps().count(), FREE_ATTEMPTS as usize);
+        assert_eq!(attempts.delay(now), Duration::from_secs(1));
+        let later = now + chrono::Duration::milliseconds(400);
+        assert_eq!(attempts.delay(later), Duration::from_millis(600));
+
+        attempts.record_success().unwrap();
+        let attempts = Attempts::load(&path).unwrap();
+        assert_eq!(attempts.failures(), 0);
+        assert_eq!(attempts.delay(now), Duration::ZERO);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let attempts = Attempts {
+            failures: 1000,
+            timestamps: vec![now.timestamp_millis()],
+            path: PathBuf::new(),
+        };
+        assert_eq!(attempts.delay(now), MAX_BACKOFF);
+        let later = now + chrono::Duration::seconds(5);
+        assert_eq!(attempts.delay(later), MAX_BACKOFF - Duration::from_secs(5));
+    }
+}
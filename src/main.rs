@@ -1,20 +1,27 @@
-#![feature(if_let_guard)]
-
 #[macro_use]
 extern crate prettytable;
 
 mod components;
-use components::cryptography::encryption::Cipher;
+use components::cryptography::encryption::{Cipher, KeyScheme};
 use components::logindata::LoginData;
-use components::{console, cryptography::*, database, error, export, metadata::Metadata};
+use components::{
+    console, container, cryptography::*, database, error, export, hardening, history,
+    integrity,
+    metadata::{KeySlot, Metadata},
+};
+use error::DataStorageError;
+use components::throttle::Attempts;
+use kdf::KdfParams;
+use recovery::{RecoveryKey, RecoveryShare};
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::process;
 
-use aes_kw::KekAes256;
+use chrono::Utc;
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 use tokio::time::{self, Duration};
+use zeroize::Zeroizing;
 
 /// Lazily handles unrecoverable errors.
 /// Receives an `Result` where `E`  `std::fmt::Display`.
@@ -34,8 +41,49 @@ macro_rules! unrecoverable {
 /// Wrapper of `run`. Makes sure that things are propperly configured.
 #[tokio::main]
 async fn main() {
+    // Before any secret is read: no core dumps, no debugger. Secrets lock their own pages out of swap.
+    for warning in hardening::harden_process() {
+        eprintln!("Warning: {warning}");
+    }
+
+    // Arguments: `--keyfile <path>`, `--shares` to unlock with recovery key shares,
+    // `--duress` to set the duress password, `--vault <path>` to open a single-file vault
+    // and `--export-vault <path>`/`--import-vault <path>` to convert the data folder.
+    let mut args = std::env::args().skip(1);
+    let mut keyfile_path = None;
+    let mut use_shares = false;
+    let mut set_duress = false;
+    let mut vault_path = None;
+    let mut export_path = None;
+    let mut import_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keyfile" if let Some(path) = args.next() => keyfile_path = Some(PathBuf::from(path)),
+            "--shares" => use_shares = true,
+            "--duress" => set_duress = true,
+            "--vault" if let Some(path) = args.next() => vault_path = Some(PathBuf::from(path)),
+            "--export-vault" if let Some(path) = args.next() => export_path = Some(PathBuf::from(path)),
+            "--import-vault" if let Some(path) = args.next() => import_path = Some(PathBuf::from(path)),
+            _ => {
+                eprintln!("Usage: password-guardian [--keyfile <path>] [--shares] [--duress] [--vault <path>]");
+                eprintln!("       password-guardian [--keyfile <path>] --export-vault <path> | --import-vault <path>");
+                exit(2);
+            }
+        }
+    }
+
     // Prepare: Define variables
-    let folder_path = PathBuf::from("./data/");
+    // A single-file vault is unpacked into a private working folder while it is open.
+    let folder_path = match &vault_path {
+        Some(_) => match container::working_folder() {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        },
+        None => PathBuf::from("./data/"),
+    };
 
     let mut database_path = folder_path.clone();
     database_path.push("data.db");
@@ -43,28 +91,181 @@ async fn main() {
     let mut metadata_path = folder_path.clone();
     metadata_path.push("encryption.json");
 
-    if !database_path.exists() && !metadata_path.exists() {
-        initialize(&metadata_path, &database_path);
+    // Failed attempts must outlive the working folder of a single-file vault.
+    let attempts_path = match &vault_path {
+        Some(vault_path) => {
+            let mut path = vault_path.as_os_str().to_owned();
+            path.push(".attempts.json");
+            PathBuf::from(path)
+        }
+        None => folder_path.join("attempts.json"),
+    };
+
+    // The decoy vault opened by the duress password, named like a copy of the vault so
+    // nothing tells it is a decoy.
+    let mut decoy_database_path = folder_path.clone();
+    decoy_database_path.push("backup.db");
+
+    let mut decoy_metadata_path = folder_path.clone();
+    decoy_metadata_path.push("backup.json");
+
+    if export_path.is_some() || import_path.is_some() {
+        let keyfile = match keyfile_path.as_deref().map(read_keyfile).transpose() {
+            Ok(keyfile) => keyfile,
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        };
+        let result = match (&export_path, &import_path) {
+            (Some(path), _) => export_vault(&metadata_path, &database_path, path, keyfile.as_deref()),
+            (_, Some(path)) => import_vault(path, &metadata_path, &database_path, keyfile.as_deref()),
+            _ => unreachable!(),
+        };
+        match result {
+            Ok(_) => println!("Vault converted successfully"),
+            Err(e) => {
+                eprintln!("ERROR: Unable to convert the vault. {e}");
+                exit(1);
+            }
+        }
+        exit(0);
+    }
+    if let Some(vault_path) = vault_path.as_deref().filter(|path| path.exists()) {
+        if let Err(e) = container::unpack_metadata(vault_path, &metadata_path) {
+            eprintln!("{e}");
+            exit(1);
+        }
+    }
+
+    let keyfile_path = if !database_path.exists() && !metadata_path.exists() {
+        initialize(&metadata_path, &database_path, keyfile_path.as_deref())
+    } else {
+        keyfile_path
+    };
+    if set_duress && vault_path.is_some() {
+        eprintln!("A single-file vault cannot have a duress password.");
+        exit(2);
+    }
+    if set_duress {
+        let keyfile = match keyfile_path.as_deref().map(read_keyfile).transpose() {
+            Ok(keyfile) => keyfile,
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        };
+        match set_duress_password(
+            &metadata_path,
+            (&decoy_metadata_path, &decoy_database_path),
+            keyfile.as_deref(),
+        ) {
+            Ok(true) => println!("Duress password set successfully"),
+            Ok(false) => println!("Duress password removed successfully"),
+            Err(e) => {
+                eprintln!("ERROR: Unable to set duress password. {e}");
+                exit(1);
+            }
+        }
+        exit(0);
     }
-    let exit_code = run(&metadata_path, &database_path).await;
-    process::exit(exit_code);
+    let exit_code = run(
+        &metadata_path,
+        &database_path,
+        (&decoy_metadata_path, &decoy_database_path),
+        &attempts_path,
+        keyfile_path.as_deref(),
+        use_shares,
+        vault_path.as_deref(),
+    )
+    .await;
+    exit(exit_code);
+}
+
+/// Exit with `code`, deleting the working folder of a single-file vault first.
+fn exit(code: i32) -> ! {
+    container::remove_working_folder();
+    process::exit(code)
 }
 
 /// The main logic.
-/// Ask for master password and enters read-eval-print loop.
-async fn run(metadata_path: &Path, database_path: &Path) -> i32 {
-    // Connect to database and read metadata file;
+/// Ask for master password, or recovery key shares if `use_shares`, and enters read-eval-print loop.
+/// The duress password opens the decoy vault at `decoy_paths` instead, which looks and
+/// behaves like any other vault.
+/// With `vault_path`, the vault is kept in that single file and saved to it after every operation.
+async fn run(
+    metadata_path: &Path,
+    database_path: &Path,
+    decoy_paths: (&Path, &Path),
+    attempts_path: &Path,
+    keyfile_path: Option<&Path>,
+    use_shares: bool,
+    vault_path: Option<&Path>,
+) -> i32 {
+    // Read metadata files;
     let context = unrecoverable!(Metadata::from_file(metadata_path));
-    let conn = unrecoverable!(database::database_connection(database_path));
+    let decoy = match decoy_paths.0.exists() {
+        true => Some(unrecoverable!(Metadata::from_file(decoy_paths.0))),
+        false => None,
+    };
+    let mut attempts = unrecoverable!(Attempts::load(attempts_path));
+
+    // Hash the keyfile if given, or ask for it if no key slot opens without one.
+    let keyfile = match keyfile_path {
+        Some(path) => Some(unrecoverable!(read_keyfile(path))),
+        None if context.requires_keyfile() && !use_shares => {
+            let has_recovery = context.slots().iter().any(KeySlot::is_recovery);
+            let path = match has_recovery {
+                true => console::input("Keyfile path (empty to use the recovery key): ", true),
+                false => console::input("Keyfile path: ", false),
+            };
+            match path.is_empty() {
+                true => None,
+                false => Some(unrecoverable!(read_keyfile(Path::new(&path)))),
+            }
+        }
+        None => None,
+    };
 
     // Check master password.
+    // Failures are recorded on disk and slow down further attempts, even across runs.
     let mut incorrect_counter = 0;
-    let cipher = loop {
-        let password = rpassword::prompt_password("Master Password: ").unwrap();
-        let kek = kdf::derive_kek(&password, &unrecoverable!(context.kek_salt()));
-        match encryption::Cipher::from_unwrap(kek, unrecoverable!(context.wrap())) {
-            Ok(c) => break c,
-            Err(_) => {
+    let (cipher, password, is_decoy) = loop {
+        let delay = attempts.delay(Utc::now());
+        if !delay.is_zero() {
+            println!("Too many failed attempts, please wait {} seconds.", delay.as_secs_f32().ceil());
+            time::sleep(delay).await;
+        }
+        if context.lockout().is_some_and(|lockout| attempts.failures() >= lockout) {
+            println!("Too many failed attempts: only a keyfile or the recovery key can unlock the vault.");
+        }
+        let password = match use_shares {
+            true => read_shares(),
+            false => console::password("Master Password: "),
+        };
+        let unlocked = match &decoy {
+            None => context
+                .unlock_after(attempts.failures(), &password, keyfile.as_deref())
+                .map(|(_, c)| (c, false)),
+            // Try every slot of both vaults so the unlock time does not tell them apart.
+            // The decoy opens without a keyfile, even if the real vault cannot.
+            Some(decoy) => {
+                let failures = attempts.failures();
+                let real = context.unlock_every_slot(failures, &password, keyfile.as_deref());
+                let fake = decoy.unlock_every_slot(failures, &password, keyfile.as_deref());
+                match (real, fake) {
+                    (
+                        Err(DataStorageError::IncorrectPassword | DataStorageError::KeyfileRequired),
+                        Ok((_, c)),
+                    ) => Ok((c, true)),
+                    (real, _) => real.map(|(_, c)| (c, false)),
+                }
+            }
+        };
+        match unlocked {
+            Ok((c, is_decoy)) => break (c, password, is_decoy),
+            Err(DataStorageError::IncorrectPassword) => {
+                unrecoverable!(attempts.record_failure(Utc::now()));
                 incorrect_counter += 1;
                 if incorrect_counter == 3 {
                     eprintln!("\nPassword Manager: 3 incorrect password attempts.");
@@ -72,31 +273,133 @@ async fn run(metadata_path: &Path, database_path: &Path) -> i32 {
                 }
                 println!("Sorry, try again.\n");
             }
+            Err(e) => {
+                eprintln!("{e}");
+                return 1;
+            }
         };
     };
 
-    repl(&cipher, &conn).await
+    // From here on the decoy vault is handled like the real one.
+    let (context, metadata_path, database_path) = match is_decoy {
+        true => (decoy.unwrap(), decoy_paths.0, decoy_paths.1),
+        false => (context, metadata_path, database_path),
+    };
+
+    // The metadata must have been written by someone holding the unwrapped key.
+    unrecoverable!(context.verify(&cipher));
+    // A single-file vault keeps its database encrypted until now.
+    if let Some(vault_path) = vault_path.filter(|_| !database_path.exists()) {
+        unrecoverable!(container::unpack_database(vault_path, &cipher, database_path));
+    }
+    let database_key = unrecoverable!(context.database_key(&cipher));
+    let conn = unrecoverable!(database::database_connection(
+        database_path,
+        database_key.as_deref()
+    ));
+
+    if attempts.failures() > 0 {
+        println!("\n{} failed unlock attempts since the last unlock:", attempts.failures());
+        for time in attempts.timestamps() {
+            println!("  - {}", time.to_rfc2822());
+        }
+        unrecoverable!(attempts.record_success());
+        console::input("Press Enter to continue.", true);
+    }
+
+    let cipher = unrecoverable!(recover_rotation(
+        cipher,
+        &password,
+        keyfile.as_deref(),
+        &conn,
+        metadata_path
+    ));
+
+    // Check that the database has not been tampered with since it was last written.
+    match unrecoverable!(integrity::verify(&conn, &cipher, context.database_sealed())) {
+        Some(report) if report.is_intact() => {}
+        Some(report) => {
+            eprintln!("\n{report}");
+            if console::input("Accept the current content of the database? [y/N]: ", true) != "y" {
+                return 1;
+            }
+        }
+        None => {}
+    }
+
+    // Bring items stored by older versions up to date, purge the trash and record the
+    // trusted state.
+    let transaction = unrecoverable!(conn.unchecked_transaction());
+    unrecoverable!(database::migrate(&transaction, &cipher));
+    let purged = unrecoverable!(database::purge_trash(
+        &transaction,
+        &cipher,
+        context.trash_retention()
+    ));
+    unrecoverable!(integrity::seal(&transaction, &cipher));
+    unrecoverable!(transaction.commit());
+    // The copy taken before migrating keeps every row as it was, deleted ones included.
+    if unrecoverable!(database::remove_backups(database_path)) > 0 {
+        println!("The database was upgraded, its copy in the previous format has been deleted.");
+    }
+    if purged > 0 {
+        unrecoverable!(database::vacuum(&conn));
+        println!("{purged} items were deleted for good after their time in the trash.");
+    }
+
+    // Authenticate metadata written before it carried a MAC, and record that the database
+    // is sealed from now on.
+    let mut context = unrecoverable!(Metadata::from_file(metadata_path));
+    if !context.is_sealed() || !context.database_sealed() {
+        context.set_database_sealed();
+        unrecoverable!(context.write_metadata(metadata_path, &cipher));
+    }
+    let cipher = unrecoverable!(upgrade_key_scheme(cipher, &conn, metadata_path));
+
+    repl(
+        cipher,
+        &conn,
+        metadata_path,
+        database_path,
+        vault_path,
+        keyfile.as_deref(),
+    )
+    .await
 }
 
 // REPL: Handle operations queries
-async fn repl(cipher: &Cipher, conn: &rusqlite::Connection) -> i32 {
+async fn repl(
+    mut cipher: Cipher,
+    conn: &rusqlite::Connection,
+    metadata_path: &Path,
+    database_path: &Path,
+    vault_path: Option<&Path>,
+    keyfile: Option<&[u8; 32]>,
+) -> i32 {
     let stdin = io::stdin();
     let handle = BufReader::new(stdin);
     let timeout_duration = Duration::from_secs(120);
 
     let mut lines = handle.lines();
 
-    let mut selection: Option<LoginData> = None;
+    // Display index and item chosen in the main menu
+    let mut selection: Option<(usize, LoginData)> = None;
     print!("\x1B[2J\x1B[1;1H");
 
-    let data = unrecoverable!(database::retrieve_all(conn));
+    let mut data = unrecoverable!(database::retrieve_active(conn, &cipher));
     loop {
+        // Save the single-file vault after every operation.
+        if let Some(vault_path) = vault_path {
+            if let Err(e) = container::pack(vault_path, metadata_path, database_path, &cipher) {
+                println!("ERROR: Unable to save the vault file. {e}");
+            }
+        }
         println!();
 
         // If user has already chosen an item
-        if let Some(item) = selection.take() {
-            console::print_table([&item].into_iter());
-            console::item_operation_prompt(item.id, &item.name);
+        if let Some((index, item)) = selection.take() {
+            console::print_table([(index, &item)].into_iter());
+            console::item_operation_prompt(index, &item);
 
             // Read input
             // Terminate if reached timeout before next user input
@@ -109,16 +412,91 @@ async fn repl(cipher: &Cipher, conn: &rusqlite::Connection) -> i32 {
             };
             match line.trim() {
                 "remove" => {
-                    unrecoverable!(database::delete_login(conn, item.id));
-                    println!("Item removed successfully")
+                    if console::input("Move this item to the trash? [y/N]: ", true) != "y" {
+                        println!("Back to main menu.\n");
+                        continue;
+                    }
+                    let mut login = item.clone();
+                    login.move_to_trash();
+                    let transaction = unrecoverable!(conn.unchecked_transaction());
+                    unrecoverable!(database::update_login(&transaction, &cipher, &login));
+                    unrecoverable!(integrity::seal(&transaction, &cipher));
+                    unrecoverable!(transaction.commit());
+                    data = unrecoverable!(database::retrieve_active(conn, &cipher));
+                    println!("Item moved to the trash, `restore` brings it back")
                 }
                 "update" => {
-                    todo!()
+                    println!("Press Enter to keep the current value.");
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    let mut login = item.clone();
+                    login.name = console::input_or_keep("Login name", &item.name);
+                    login.username = console::input_or_keep("Email", &item.username);
+                    let password = loop {
+                        let password = console::password("New password (empty to keep it): ");
+                        if password.is_empty()
+                            || *console::password("Retype password: ") == *password
+                        {
+                            break password;
+                        }
+                        println!("Password not match. Please try again.\n");
+                    };
+                    handler.abort();
+
+                    if login.name == item.name && login.username == item.username && password.is_empty() {
+                        println!("Nothing to update.");
+                        continue;
+                    }
+                    if !password.is_empty() {
+                        login.set_password(&cipher, password.as_bytes());
+                    }
+                    login.touch();
+                    let transaction = unrecoverable!(conn.unchecked_transaction());
+                    if !password.is_empty() {
+                        unrecoverable!(history::retire_password(&transaction, &cipher, &item));
+                    }
+                    unrecoverable!(database::update_login(&transaction, &cipher, &login));
+                    unrecoverable!(integrity::seal(&transaction, &cipher));
+                    unrecoverable!(transaction.commit());
+                    data = unrecoverable!(database::retrieve_active(conn, &cipher));
+                    println!("Item updated successfully")
                 }
                 "password" => {
-                    let password =
-                        String::from_utf8(cipher.decrypt(&item.password).unwrap()).unwrap();
-                    println!("The password is as follow:\n{}", password);
+                    let password = unrecoverable!(item.decrypt_password(&cipher));
+                    println!("The password is as follow:\n{}", String::from_utf8_lossy(&password));
+                }
+                "history" => {
+                    let entries = unrecoverable!(history::retrieve(conn, &cipher, &item.uuid));
+                    if entries.is_empty() {
+                        println!("The password of this item has never been changed.");
+                        continue;
+                    }
+                    console::print_history(&entries);
+                    let choice = console::input("Select a previous password to show, or press Enter to get back: ", true);
+                    let Some(entry) = choice.parse::<usize>().ok().and_then(|i| i.checked_sub(1)).and_then(|i| entries.get(i)) else {
+                        println!("Back to main menu.\n");
+                        continue;
+                    };
+                    let password = unrecoverable!(entry.decrypt_password(&cipher));
+                    println!("The password is as follow:\n{}", String::from_utf8_lossy(&password));
+                    if console::input("Restore it as the current password? [y/N]: ", true) != "y" {
+                        continue;
+                    }
+                    // The current password takes the place of the restored one in the history.
+                    let mut login = item.clone();
+                    login.set_password(&cipher, &password);
+                    login.touch();
+                    let transaction = unrecoverable!(conn.unchecked_transaction());
+                    unrecoverable!(history::retire_password(&transaction, &cipher, &item));
+                    unrecoverable!(history::delete(&transaction, entry.id));
+                    unrecoverable!(database::update_login(&transaction, &cipher, &login));
+                    unrecoverable!(integrity::seal(&transaction, &cipher));
+                    unrecoverable!(transaction.commit());
+                    data = unrecoverable!(database::retrieve_active(conn, &cipher));
+                    println!("Password restored successfully")
                 }
                 _ => println!("Back to main menu.\n"),
             }
@@ -144,33 +522,215 @@ async fn repl(cipher: &Cipher, conn: &rusqlite::Connection) -> i32 {
                     let handler = tokio::spawn(async {
                         time::sleep(Duration::from_secs(60)).await;
                         eprintln!("\nPassword Manager: Timeout reached, aborting.");
-                        process::exit(1)
+                        exit(1)
                     });
                     let name = console::input("Login name: ", false);
                     let username = console::input("Email: ", false);
                     let password = loop {
-                        let password = rpassword::prompt_password("Password: ").unwrap();
-                        if rpassword::prompt_password("Retype password: ").unwrap() != password {
+                        let password = console::password("Password: ");
+                        if *console::password("Retype password: ") != *password {
                             println!("Password not match. Please try again.\n");
                         } else {
                             break password;
                         }
                     };
-                    let encrypted_password = cipher.encrypt(password.as_bytes());
+                    let login = LoginData::new(name, username, password.as_bytes(), &cipher);
                     handler.abort();
-                    database::insert_login(conn, LoginData::new(name, username, encrypted_password));
+                    let transaction = unrecoverable!(conn.unchecked_transaction());
+                    database::insert_login(&transaction, &cipher, login);
+                    unrecoverable!(integrity::seal(&transaction, &cipher));
+                    unrecoverable!(transaction.commit());
+                    data = unrecoverable!(database::retrieve_active(conn, &cipher));
                 },
                 "display" => {
-                    console::print_table(data.iter());
+                    console::print_table((1..).zip(data.iter()));
                 },
                 "export" => {
-                    match export::decrypt_and_export(data.iter(), cipher) {
+                    match export::decrypt_and_export(data.iter(), &cipher) {
                         Ok(_) => println!("Passwords successfully exported to `password.csv`"),
                         Err(e) => println!("ERROR: Unable to export due to {e}")
                     }
                 }
+                "passwd" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match change_master_password(metadata_path, keyfile) {
+                        Ok(_) => println!("Master password changed successfully"),
+                        Err(e) => println!("ERROR: Unable to change master password. {e}"),
+                    }
+                    handler.abort();
+                },
+                "kdf" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match Metadata::from_file(metadata_path) {
+                        Ok(context) => console::print_key_slots(context.slots()),
+                        Err(e) => println!("ERROR: {e}"),
+                    }
+                    println!("The key slot opened by the current master password is upgraded.");
+                    match upgrade_kdf(metadata_path, keyfile) {
+                        Ok(_) => println!("Key derivation parameters upgraded successfully"),
+                        Err(e) => println!("ERROR: Unable to upgrade key derivation. {e}"),
+                    }
+                    handler.abort();
+                },
+                "rotate-key" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match rotate_key(&cipher, conn, metadata_path, keyfile) {
+                        Ok(c) => {
+                            cipher = c;
+                            data = unrecoverable!(database::retrieve_active(conn, &cipher));
+                            println!("Encryption key rotated successfully");
+                        }
+                        Err(e) => println!("ERROR: Unable to rotate encryption key. {e}"),
+                    }
+                    handler.abort();
+                },
+                "slots" => {
+                    match Metadata::from_file(metadata_path) {
+                        Ok(context) => console::print_key_slots(context.slots()),
+                        Err(e) => println!("ERROR: {e}"),
+                    }
+                },
+                "add-slot" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match add_key_slot(metadata_path, keyfile) {
+                        Ok(_) => println!("Key slot added successfully"),
+                        Err(e) => println!("ERROR: Unable to add key slot. {e}"),
+                    }
+                    handler.abort();
+                },
+                "label-slot" => {
+                    match label_key_slot(metadata_path, &cipher) {
+                        Ok(_) => println!("Key slot renamed successfully"),
+                        Err(e) => println!("ERROR: Unable to rename key slot. {e}"),
+                    }
+                },
+                "revoke-slot" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match revoke_key_slot(metadata_path, keyfile) {
+                        Ok(_) => println!("Key slot revoked successfully"),
+                        Err(e) => println!("ERROR: Unable to revoke key slot. {e}"),
+                    }
+                    handler.abort();
+                },
+                "lockout" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match set_lockout(metadata_path, keyfile) {
+                        Ok(_) => println!("Lockout policy updated successfully"),
+                        Err(e) => println!("ERROR: Unable to update lockout policy. {e}"),
+                    }
+                    handler.abort();
+                },
+                "split-key" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match split_recovery_key(metadata_path, keyfile) {
+                        Ok(shares) => {
+                            println!("\nHand out one share to each person, they are not shown again:\n");
+                            for (i, share) in shares.iter().enumerate() {
+                                println!("    Share {}: {share}", i + 1);
+                            }
+                            println!("\nUnlock with `--shares` and enter the shares one after another.");
+                        }
+                        Err(e) => println!("ERROR: Unable to split a recovery key. {e}"),
+                    }
+                    handler.abort();
+                },
+                "trash" => {
+                    let trash = unrecoverable!(database::retrieve_trash(conn, &cipher));
+                    console::print_trash(&trash);
+                    match Metadata::from_file(metadata_path).map(|context| context.trash_retention()) {
+                        Ok(0) => println!("Items stay in the trash until it is emptied."),
+                        Ok(days) => println!("Items are deleted for good after {days} days in the trash."),
+                        Err(e) => println!("ERROR: {e}"),
+                    }
+                },
+                "restore" => {
+                    let trash = unrecoverable!(database::retrieve_trash(conn, &cipher));
+                    if trash.is_empty() {
+                        println!("The trash is empty.");
+                        continue;
+                    }
+                    console::print_trash(&trash);
+                    let choice = console::input("Select an item to restore, or press Enter to get back: ", true);
+                    let Some(item) = choice.parse::<usize>().ok().and_then(|i| i.checked_sub(1)).and_then(|i| trash.get(i)) else {
+                        continue;
+                    };
+                    let mut login = item.clone();
+                    login.restore();
+                    let transaction = unrecoverable!(conn.unchecked_transaction());
+                    unrecoverable!(database::update_login(&transaction, &cipher, &login));
+                    unrecoverable!(integrity::seal(&transaction, &cipher));
+                    unrecoverable!(transaction.commit());
+                    data = unrecoverable!(database::retrieve_active(conn, &cipher));
+                    println!("Item restored successfully")
+                },
+                "empty-trash" => {
+                    let trash = unrecoverable!(database::retrieve_trash(conn, &cipher));
+                    if trash.is_empty() {
+                        println!("The trash is empty.");
+                        continue;
+                    }
+                    let question = format!("Delete the {} items in the trash for good? [y/N]: ", trash.len());
+                    if console::input(&question, true) != "y" {
+                        continue;
+                    }
+                    let transaction = unrecoverable!(conn.unchecked_transaction());
+                    unrecoverable!(database::empty_trash(&transaction, &cipher));
+                    unrecoverable!(integrity::seal(&transaction, &cipher));
+                    unrecoverable!(transaction.commit());
+                    unrecoverable!(database::vacuum(conn));
+                    println!("Trash emptied successfully")
+                },
+                "retention" => {
+                    println!();
+                    let handler = tokio::spawn(async {
+                        time::sleep(Duration::from_secs(60)).await;
+                        eprintln!("\nPassword Manager: Timeout reached, aborting.");
+                        exit(1)
+                    });
+                    match set_trash_retention(metadata_path, keyfile) {
+                        Ok(_) => println!("Trash retention updated successfully"),
+                        Err(e) => println!("ERROR: Unable to update trash retention. {e}"),
+                    }
+                    handler.abort();
+                },
                 "search" => {
-                    todo!()
+                    let query = console::input("Search: ", true).to_lowercase();
+                    console::print_table((1..).zip(data.iter()).filter(|(_, item)| item.matches(&query)));
                 },
                 "quit" => {
                     println!("Exit.");
@@ -179,10 +739,9 @@ async fn repl(cipher: &Cipher, conn: &rusqlite::Connection) -> i32 {
 
                 // If user selects an item
                 x if let Ok(index) = x.parse::<usize>() => {
-                    if index <= data.len() {
-                        selection = Some(data[index - 1].clone());
-                    } else {
-                        eprintln!("Invalid index");
+                    match index.checked_sub(1).and_then(|i| data.get(i)) {
+                        Some(item) => selection = Some((index, item.clone())),
+                        None => eprintln!("Invalid index"),
                     }
                 },
                 _ => println!("Invalid option"),
@@ -191,26 +750,564 @@ async fn repl(cipher: &Cipher, conn: &rusqlite::Connection) -> i32 {
     }
 }
 
-pub fn initialize(metadata_path: &Path, database_path: &Path) {
+/// Create a new vault. Returns the path of its keyfile, if it has one.
+pub fn initialize(
+    metadata_path: &Path,
+    database_path: &Path,
+    keyfile_path: Option<&Path>,
+) -> Option<PathBuf> {
     File::create(metadata_path).unwrap();
     File::create(database_path).unwrap();
 
-    let conn = database::database_connection(database_path).unwrap();
+    let master_password = console::init_master_password();
+    let keyfile_path = keyfile_path.map(Path::to_path_buf).or_else(|| {
+        let answer = console::input("Protect the vault with a keyfile as well? [y/N]: ", true);
+        (answer == "y").then(|| PathBuf::from(console::input("Keyfile path: ", false)))
+    });
+    let keyfile = keyfile_path.as_ref().map(|path| {
+        if !path.exists() {
+            kdf::generate_keyfile(path).unwrap();
+            println!("Generated a new keyfile at {}.", path.display());
+        }
+        println!("Keep the keyfile outside of the data folder, the vault cannot be unlocked without it.\n");
+        read_keyfile(path).unwrap()
+    });
+    let cipher = Cipher::generate();
+    println!("Calibrating key derivation for this machine.");
+    let target = console::input_number("Target unlock time in milliseconds", 1000);
+    let max_memory = console::input_number("Memory limit for key derivation in MiB", 1024);
+    let kdf = kdf::calibrate(
+        Duration::from_millis(target.into()),
+        max_memory.saturating_mul(1024),
+    );
+    println!("Key derivation: {kdf}\n");
+
+    // Optionally encrypt the whole database file on top of its fields.
+    let database_key = cfg!(feature = "sqlcipher")
+        .then(|| {
+            console::input(
+                "Encrypt the whole database file as well, hiding its structure and size? [y/N]: ",
+                true,
+            )
+        })
+        .filter(|answer| answer == "y")
+        .map(|_| generate_key());
+    let conn = database::database_connection(database_path, database_key.as_deref()).unwrap();
     database::create_table(&conn).unwrap();
+    integrity::seal(&conn, &cipher).unwrap();
 
-    let master_password = console::init_master_password();
-    let mut enc_key = encryption::Cipher::generate_key();
-    let salt = generate_salt();
-    let kek = KekAes256::new(&kdf::derive_kek(&master_password, &salt).into());
-
-    let mut wrap = [0u8; 40];
-    kek.wrap(
-        &base64_url::decode(base64_url::escape_in_place(&mut enc_key)).unwrap(),
-        &mut wrap,
+    let slot = KeySlot::new(
+        "default".into(),
+        &cipher,
+        &master_password,
+        kdf.clone(),
+        keyfile.as_deref(),
     )
     .unwrap();
+    let recovery_key = RecoveryKey::generate();
+    let recovery_slot = KeySlot::recovery("recovery key".into(), &cipher, &recovery_key, kdf).unwrap();
+
+    let mut metadata = Metadata::new(vec![slot, recovery_slot]);
+    metadata.set_database_key(&cipher, database_key.as_deref());
+    metadata.set_database_sealed();
+    metadata.write_metadata(metadata_path, &cipher).unwrap();
+    println!("Your recovery key is:\n\n    {recovery_key}\n");
+    println!("Write it down and keep it somewhere safe, it will not be shown again.");
+    println!("If you forget the master password, enter the recovery key at the password prompt.\n");
+    keyfile_path
+}
+
+/// Change the master password of the key slot it opens.
+/// Verifies the current password, then re-wraps the same encryption key under a KEK
+/// derived from the new password and a fresh salt. Stored passwords are left untouched.
+/// Opening with the recovery key resets the first master password slot instead.
+pub fn change_master_password(
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (index, cipher, old_password) = verify_master_password(&context, keyfile)?;
+
+    let new_password = console::new_master_password();
+    if *new_password == *old_password {
+        return Err(DataStorageError::PasswordUnchanged);
+    }
 
-    let md = Metadata::new(base64_url::encode(&wrap), [base64_url::encode(&salt)]);
+    let index = match context.slots()[index].is_recovery() {
+        true => context.slots().iter().position(|slot| !slot.is_recovery()),
+        false => Some(index),
+    };
+    match index {
+        Some(index) => {
+            let slot = context.slot_mut(index)?;
+            let keyfile = keyfile.filter(|_| slot.requires_keyfile());
+            *slot = slot.renew(&cipher, &new_password, slot.kdf().clone(), keyfile)?;
+        }
+        None => context.add_slot(KeySlot::new(
+            "default".into(),
+            &cipher,
+            &new_password,
+            KdfParams::default(),
+            keyfile,
+        )?),
+    }
+    context.write_metadata(metadata_path, &cipher)
+}
+
+/// Upgrade the key derivation parameters of the key slot opened by the master password.
+/// Re-derives the KEK from the master password with the new parameters, which default to the
+/// current ones of the slot, and re-wraps the encryption key.
+/// Lowering the memory or time cost must be confirmed, otherwise it is refused.
+pub fn upgrade_kdf(
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (index, cipher, password) = verify_master_password(&context, keyfile)?;
+
+    let slot = context.slot_mut(index)?;
+    let mem_cost = console::input_number("Memory cost in MiB", slot.kdf().mem_cost / 1024);
+    let time_cost = console::input_number("Iterations", slot.kdf().time_cost);
+    let kdf = KdfParams::argon2id(mem_cost.saturating_mul(1024), time_cost);
+    if kdf.is_weaker_than(slot.kdf()) {
+        println!("Current key derivation: {}", slot.kdf());
+        println!("New key derivation:     {kdf}");
+        if console::input("This makes the master password easier to brute-force. Downgrade anyway? [y/N]: ", true) != "y" {
+            return Err(DataStorageError::KdfDowngrade);
+        }
+    }
+    let keyfile = keyfile.filter(|_| slot.requires_keyfile());
+    *slot = slot.renew(&cipher, &password, kdf, keyfile)?;
+    context.write_metadata(metadata_path, &cipher)
+}
 
-    md.write_metadata(metadata_path).unwrap();
+/// Write the vault of the data folder into the single file at `vault_path`.
+pub fn export_vault(
+    metadata_path: &Path,
+    database_path: &Path,
+    vault_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    if vault_path.exists() {
+        return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+    }
+    let context = Metadata::from_file(metadata_path)?;
+    let (_, cipher, _) = verify_master_password(&context, keyfile)?;
+    container::pack(vault_path, metadata_path, database_path, &cipher)
+}
+
+/// Unpack the single file at `vault_path` into the data folder, which must not hold a vault yet.
+pub fn import_vault(
+    vault_path: &Path,
+    metadata_path: &Path,
+    database_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    if metadata_path.exists() || database_path.exists() {
+        return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+    }
+    if let Some(folder) = metadata_path.parent() {
+        fs::create_dir_all(folder)?;
+    }
+    container::unpack_metadata(vault_path, metadata_path)?;
+    let unpacked = Metadata::from_file(metadata_path)
+        .and_then(|context| verify_master_password(&context, keyfile))
+        .and_then(|(_, cipher, _)| container::unpack_database(vault_path, &cipher, database_path));
+    if unpacked.is_err() {
+        _ = fs::remove_file(metadata_path);
+    }
+    unpacked
+}
+
+/// Set the duress password, which opens a separate decoy vault instead of this one.
+/// The decoy is an ordinary vault at `decoy_paths`, replacing any previous decoy and its entries.
+/// An empty duress password removes the decoy vault. Returns whether a decoy vault is set.
+pub fn set_duress_password(
+    metadata_path: &Path,
+    decoy_paths: (&Path, &Path),
+    keyfile: Option<&[u8; 32]>,
+) -> Result<bool, DataStorageError> {
+    let (decoy_metadata_path, decoy_database_path) = decoy_paths;
+    let context = Metadata::from_file(metadata_path)?;
+    let (index, real_cipher, _) = verify_master_password(&context, keyfile)?;
+
+    if decoy_metadata_path.exists()
+        && console::input(
+            "A duress password is already set. Replace the decoy vault and delete its entries? [y/N]: ",
+            true,
+        ) != "y"
+    {
+        return Ok(true);
+    }
+    let duress_password = console::new_duress_password();
+    if !duress_password.is_empty() && context.unlock(&duress_password, keyfile).is_ok() {
+        return Err(DataStorageError::DuressPasswordInUse);
+    }
+    for path in [decoy_metadata_path, decoy_database_path] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    if duress_password.is_empty() {
+        return Ok(false);
+    }
+
+    // Same database mode and key slots as the real vault; the recovery key is discarded.
+    let database_key = context
+        .database_key(&real_cipher)?
+        .map(|_| generate_key());
+    let cipher = Cipher::generate();
+    File::create(decoy_database_path)?;
+    let conn = database::database_connection(decoy_database_path, database_key.as_deref())?;
+    database::create_table(&conn)?;
+    integrity::seal(&conn, &cipher)?;
+
+    let kdf = context.slots()[index].kdf().clone();
+    let slot = KeySlot::new("default".into(), &cipher, &duress_password, kdf.clone(), None)?;
+    let recovery_slot =
+        KeySlot::recovery("recovery key".into(), &cipher, &RecoveryKey::generate(), kdf)?;
+    File::create(decoy_metadata_path)?;
+    let mut decoy = Metadata::new(vec![slot, recovery_slot]);
+    decoy.set_database_key(&cipher, database_key.as_deref());
+    decoy.set_database_sealed();
+    decoy.write_metadata(decoy_metadata_path, &cipher)?;
+    Ok(true)
+}
+
+/// Set how many consecutive failed unlocks are allowed before only slots requiring a keyfile
+/// and the recovery key are accepted. `0` disables the policy.
+pub fn set_lockout(
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (_, cipher, _) = verify_master_password(&context, keyfile)?;
+
+    let lockout = console::input_number(
+        "Failed attempts before only a keyfile or the recovery key unlocks, 0 to disable",
+        context.lockout().unwrap_or(0),
+    );
+    context.set_lockout(Some(lockout).filter(|lockout| *lockout > 0));
+    context.write_metadata(metadata_path, &cipher)
+}
+
+/// Ask for the master password and set the days items stay in the trash.
+pub fn set_trash_retention(
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (_, cipher, _) = verify_master_password(&context, keyfile)?;
+
+    let days = console::input_number(
+        "Days before items in the trash are deleted for good, 0 to keep them until emptied",
+        context.trash_retention(),
+    );
+    context.set_trash_retention(days);
+    context.write_metadata(metadata_path, &cipher)
+}
+
+/// Create a new recovery key slot and split its key into `count` shares,
+/// any `threshold` of which unlock the vault. The key itself is never shown.
+pub fn split_recovery_key(
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<Vec<RecoveryShare>, DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (index, cipher, _) = verify_master_password(&context, keyfile)?;
+
+    let count = console::input_number("Number of shares", 5);
+    let threshold = console::input_number("Shares needed to unlock", 3);
+    if threshold < 2 || threshold > count || count > u8::MAX.into() {
+        return Err(DataStorageError::InvalidShares);
+    }
+    let key = RecoveryKey::generate();
+    let kdf = context.slots()[index].kdf().clone();
+    context.add_slot(KeySlot::recovery(
+        format!("{threshold} of {count} shares"),
+        &cipher,
+        &key,
+        kdf,
+    )?);
+    context.write_metadata(metadata_path, &cipher)?;
+    Ok(key.split(threshold as u8, count as u8))
+}
+
+/// Add a key slot opening the vault with another password and, optionally, keyfile.
+pub fn add_key_slot(
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (index, cipher, _) = verify_master_password(&context, keyfile)?;
+
+    let label = console::input("Label of the new key slot: ", false);
+    let password = console::new_master_password();
+    let keyfile_path = console::input("Keyfile path for this slot (empty for none): ", true);
+    let keyfile = match keyfile_path.is_empty() {
+        true => None,
+        false => Some(read_keyfile(Path::new(&keyfile_path))?),
+    };
+    let kdf = context.slots()[index].kdf().clone();
+    context.add_slot(KeySlot::new(
+        label,
+        &cipher,
+        &password,
+        kdf,
+        keyfile.as_deref(),
+    )?);
+    context.write_metadata(metadata_path, &cipher)
+}
+
+/// Rename a key slot.
+pub fn label_key_slot(metadata_path: &Path, cipher: &Cipher) -> Result<(), DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    context.verify(cipher)?;
+    let index = select_key_slot(&context)?;
+    context.slot_mut(index)?.label = console::input("New label: ", false);
+    context.write_metadata(metadata_path, cipher)
+}
+
+/// Remove a key slot, so its password no longer opens the vault.
+pub fn revoke_key_slot(
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(), DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (_, cipher, _) = verify_master_password(&context, keyfile)?;
+
+    let index = select_key_slot(&context)?;
+    context.remove_slot(index)?;
+    context.write_metadata(metadata_path, &cipher)
+}
+
+/// Replace the encryption key.
+/// Every stored login is re-encrypted under a freshly generated key inside one transaction.
+/// Every key slot is re-wrapped, which needs its password, or recovery key shares for split
+/// recovery keys; slots whose password is not given are revoked.
+/// The new metadata is staged before the transaction commits and swapped in afterwards;
+/// `recover_rotation` finishes the job if the program dies in between.
+pub fn rotate_key(
+    cipher: &Cipher,
+    conn: &rusqlite::Connection,
+    metadata_path: &Path,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<Cipher, DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    let (index, _, password) = verify_master_password(&context, keyfile)?;
+    let new_cipher = Cipher::generate();
+    if context.slots().iter().any(KeySlot::is_recovery) {
+        println!("Recovery key slots are kept with their recovery key or enough of its shares.");
+        println!("A slot left empty is revoked: its recovery key and shares stop working for good.");
+    }
+
+    let mut keks = Vec::new();
+    for (slot_index, slot) in context.slots().iter().enumerate() {
+        keks.push(match slot_index == index {
+            true => Some(slot.derive_kek(&password, keyfile)?),
+            false => slot_kek(slot_index, slot)?,
+        });
+    }
+
+    let transaction = conn.unchecked_transaction()?;
+    database::reencrypt(&transaction, cipher, &new_cipher)?;
+    integrity::seal(&transaction, &new_cipher)?;
+
+    for (slot_index, kek) in keks.iter().enumerate().rev() {
+        match kek {
+            Some(kek) => context.slot_mut(slot_index)?.rewrap(&new_cipher, kek),
+            None => _ = context.remove_slot(slot_index)?,
+        }
+    }
+    context.set_scheme(new_cipher.scheme());
+    let database_key = context.database_key(cipher)?;
+    context.set_database_key(&new_cipher, database_key.as_deref());
+    context.write_staged(metadata_path, &new_cipher)?;
+    transaction.commit()?;
+    commit_staged_metadata(metadata_path);
+    Ok(new_cipher)
+}
+
+/// Re-encrypt a vault created before the key hierarchy with subkeys of the current key scheme.
+/// The vault key and key slots stay the same, the metadata is swapped in like for `rotate_key`.
+fn upgrade_key_scheme(
+    cipher: Cipher,
+    conn: &rusqlite::Connection,
+    metadata_path: &Path,
+) -> Result<Cipher, DataStorageError> {
+    let mut context = Metadata::from_file(metadata_path)?;
+    if context.scheme() == KeyScheme::CURRENT {
+        return Ok(cipher);
+    }
+    let new_cipher = cipher.rederive(KeyScheme::CURRENT);
+
+    let transaction = conn.unchecked_transaction()?;
+    database::reencrypt(&transaction, &cipher, &new_cipher)?;
+    integrity::seal(&transaction, &new_cipher)?;
+    context.set_scheme(KeyScheme::CURRENT);
+    let database_key = context.database_key(&cipher)?;
+    context.set_database_key(&new_cipher, database_key.as_deref());
+    context.write_staged(metadata_path, &new_cipher)?;
+    transaction.commit()?;
+    commit_staged_metadata(metadata_path);
+    Ok(new_cipher)
+}
+
+/// Swap in the metadata staged for a database that has just been committed under a new key.
+/// Neither key can be trusted for the rest of the session if that fails, so the program exits
+/// and `recover_rotation` finishes the job on the next unlock.
+fn commit_staged_metadata(metadata_path: &Path) {
+    if let Err(e) = Metadata::commit_staged(metadata_path) {
+        eprintln!("ERROR: Unable to replace the metadata after re-encrypting the database. {e}");
+        eprintln!("Unlock the vault again to finish.");
+        exit(1)
+    }
+}
+
+/// Resolve a key rotation interrupted between committing the database and replacing the metadata.
+/// Whichever key the database was last written under is kept and the other metadata is discarded.
+/// Fails if that is the staged key and the password does not open it, as the rotation revoked
+/// its key slot.
+fn recover_rotation(
+    cipher: Cipher,
+    password: &str,
+    keyfile: Option<&[u8; 32]>,
+    conn: &rusqlite::Connection,
+    metadata_path: &Path,
+) -> Result<Cipher, DataStorageError> {
+    let staged_path = Metadata::staged_path(metadata_path);
+    if !staged_path.exists() {
+        return Ok(cipher);
+    }
+
+    // Nothing is encrypted under either key if neither tells, so the current metadata will do.
+    if written_under(conn, &cipher)?.unwrap_or(true) {
+        fs::remove_file(staged_path)?;
+        return Ok(cipher);
+    }
+    let staged = Metadata::from_file(&staged_path)?;
+    if let Ok((_, staged_cipher)) = staged.unlock(password, keyfile) {
+        if written_under(conn, &staged_cipher)? == Some(true) {
+            staged.verify(&staged_cipher)?;
+            Metadata::commit_staged(metadata_path)?;
+            return Ok(staged_cipher);
+        }
+    }
+    Err(DataStorageError::RotationInterrupted)
+}
+
+/// Whether the database was last written under `cipher`, told by a stored password or, if
+/// there is none, by the seal. `None` if there is neither.
+fn written_under(
+    conn: &rusqlite::Connection,
+    cipher: &Cipher,
+) -> Result<Option<bool>, DataStorageError> {
+    if let Some(sample) = database::retrieve_rows(conn)?.first() {
+        return Ok(Some(sample.decrypt_password(cipher).is_ok()));
+    }
+    Ok(integrity::verify(conn, cipher, false)?.map(|report| !report.manifest_tampered))
+}
+
+/// Ask for the current master password and unwrap the encryption key with it.
+/// Returns the index of the key slot it opens.
+fn verify_master_password(
+    context: &Metadata,
+    keyfile: Option<&[u8; 32]>,
+) -> Result<(usize, Cipher, Zeroizing<String>), DataStorageError> {
+    let password = console::password("Current master password: ");
+    let (index, cipher) = context.unlock(&password, keyfile)?;
+    context.verify(&cipher)?;
+    Ok((index, cipher, password))
+}
+
+/// Ask for recovery key shares until enough are entered and combine them.
+/// Returns the recovered key as text, or an empty string if the shares do not match.
+fn read_shares() -> Zeroizing<String> {
+    let mut shares: Vec<RecoveryShare> = Vec::new();
+    loop {
+        let prompt = match shares.first() {
+            Some(first) => format!("Share {} of {}: ", shares.len() + 1, first.threshold()),
+            None => "Share 1: ".to_string(),
+        };
+        match RecoveryShare::parse(&console::password(&prompt)) {
+            Some(share) if shares.iter().any(|entered| entered.x() == share.x()) => {
+                println!("This share was already entered, please enter another one.");
+                continue;
+            }
+            Some(share) => shares.push(share),
+            None => {
+                println!("Invalid share, please check it for typos.");
+                continue;
+            }
+        }
+        if shares.len() >= shares[0].threshold().into() {
+            break;
+        }
+    }
+    match RecoveryKey::combine(&shares) {
+        Some(key) => key.canonical(),
+        None => {
+            println!("These shares do not recover a key.");
+            Zeroizing::new(String::new())
+        }
+    }
+}
+
+/// Ask which key slot to operate on.
+fn select_key_slot(context: &Metadata) -> Result<usize, DataStorageError> {
+    console::print_key_slots(context.slots());
+    console::input("Key slot number: ", false)
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_sub(1))
+        .filter(|index| *index < context.slots().len())
+        .ok_or(DataStorageError::KeySlotNotFound)
+}
+
+/// Ask for the password (and keyfile) of a key slot and derive its KEK.
+/// A recovery key slot takes its recovery key, or enough shares of it.
+/// Returns `None` if the password is left empty.
+fn slot_kek(
+    index: usize,
+    slot: &KeySlot,
+) -> Result<Option<hardening::Locked<[u8; 32]>>, DataStorageError> {
+    let password = match slot.is_recovery() {
+        true => console::password(&format!(
+            "Recovery key of key slot {} ({}), `shares` to enter its shares, empty to revoke it: ",
+            index + 1,
+            slot.label
+        )),
+        false => console::password(&format!(
+            "Password of key slot {} ({}), empty to revoke it: ",
+            index + 1,
+            slot.label
+        )),
+    };
+    if password.is_empty() {
+        return Ok(None);
+    }
+    let password = match password.as_str() {
+        "shares" if slot.is_recovery() => read_shares(),
+        _ => password,
+    };
+    let keyfile = match slot.requires_keyfile() {
+        true => {
+            let path = console::input(&format!("Keyfile path of key slot {}: ", index + 1), false);
+            Some(read_keyfile(Path::new(&path))?)
+        }
+        false => None,
+    };
+    let kek = slot.derive_kek(&password, keyfile.as_deref())?;
+    Cipher::from_unwrap(&kek, slot.wrap()?, KeyScheme::CURRENT).map_err(|_| DataStorageError::IncorrectPassword)?;
+    Ok(Some(kek))
+}
+
+/// Hash the keyfile at `path`.
+fn read_keyfile(path: &Path) -> Result<Zeroizing<[u8; 32]>, DataStorageError> {
+    if !path.is_file() {
+        return Err(DataStorageError::FileNotFound {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(kdf::hash_keyfile(path)?)
 }